@@ -1,68 +1,780 @@
-use cfdkim::canonicalize_signed_email;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use cfdkim::DkimPublicKey;
+use mailparse::parse_mail;
 use slog::{o, Discard, Logger};
 
 use crate::{
-    hash_bytes, process_regex_parts, remove_quoted_printable_soft_breaks, verify_dkim, Email,
-    EmailVerifierOutput, EmailWithRegex, EmailWithRegexVerifierOutput,
+    body_match_input, canonicalize_checked, extract_all_dkim_fields, extract_dkim_fields,
+    extract_from_domain, from_address_hash, is_within_domain, process_regex_parts,
+    retain_dkim_signatures, signed_raw_header, to_hex, verify_body_hash_only,
+    verify_dkim_signature_checked, verify_dkim_signatures, AuidError, CanonError,
+    CommitmentVersion, Email, EmailVerifierOutput, EmailWithRegex, EmailWithRegexVerifierOutput,
+    ExternalInput, HeaderInput, MatchTarget, RegexInfo, VerificationOutput, VerificationReport,
 };
 
+/// Error returned by the non-panicking `try_verify_*` functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The email could not be parsed or the public key could not be loaded.
+    InvalidInput(String),
+    /// The DKIM signature did not verify; carries the verification detail.
+    DkimFailed(String),
+    /// The `From` header is not covered by the `h=` tag of the DKIM signature, so the signature
+    /// does not bind the sender domain.
+    FromNotSigned,
+    /// The `From` domain is neither the `d=` domain that verified nor one of its subdomains, so
+    /// the signer does not vouch for the sender address.
+    FromDomainMismatch {
+        from_domain: String,
+        signing_domain: String,
+    },
+    /// The `i=` tag of the DKIM signature is outside its `d=` domain.
+    InvalidAuid(AuidError),
+    /// The email could not be canonicalized for its DKIM signature.
+    Canonicalization(CanonError),
+    /// The named external input is required but has no value.
+    MissingExternalInput(String),
+    /// The named external input's value is longer than its `max_length`.
+    ExternalInputTooLong {
+        name: String,
+        length: usize,
+        max_length: usize,
+    },
+    /// The header regex part at this index did not verify against the header.
+    HeaderRegexMismatch(usize),
+    /// The body regex part at this index did not verify against the body.
+    BodyRegexMismatch(usize),
+    /// The regex parts produced more captures than allowed.
+    TooManyCaptures { count: usize, max: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidInput(e) => write!(f, "Invalid verification input: {}", e),
+            Self::DkimFailed(detail) => write!(f, "DKIM verification failed: {}", detail),
+            Self::FromNotSigned => write!(f, "From header is not signed by the DKIM signature"),
+            Self::FromDomainMismatch {
+                from_domain,
+                signing_domain,
+            } => write!(
+                f,
+                "From domain {} is not within the signing domain {}",
+                from_domain, signing_domain
+            ),
+            Self::InvalidAuid(e) => write!(f, "Invalid DKIM signature identity: {}", e),
+            Self::Canonicalization(e) => write!(f, "{}", e),
+            Self::MissingExternalInput(name) => {
+                write!(f, "External input {} has no value", name)
+            }
+            Self::ExternalInputTooLong {
+                name,
+                length,
+                max_length,
+            } => write!(
+                f,
+                "External input {} is {} bytes long, exceeding its max_length of {}",
+                name, length, max_length
+            ),
+            Self::HeaderRegexMismatch(index) => {
+                write!(f, "Header regex {} failed to match", index)
+            }
+            Self::BodyRegexMismatch(index) => write!(f, "Body regex {} failed to match", index),
+            Self::TooManyCaptures { count, max } => {
+                write!(f, "Regex captures exceed the limit: {} > {}", count, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Default upper bound on the number of regex captures committed by [`verify_email_with_regex`].
+pub const DEFAULT_MAX_TOTAL_CAPTURES: usize = 64;
+
 pub fn verify_email(email: &Email) -> EmailVerifierOutput {
+    verify_email_with_version(email, CommitmentVersion::V1)
+}
+
+/// Verifies an email, computing the committed hashes as defined by `version`.
+///
+/// # Panics
+///
+/// Panics if [`try_verify_email_with_version`] returns an error.
+pub fn verify_email_with_version(email: &Email, version: CommitmentVersion) -> EmailVerifierOutput {
+    try_verify_email_with_version(email, version).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Non-panicking variant of [`verify_email`] for host-side callers that need to recover from a
+/// failed verification.
+pub fn try_verify_email(email: &Email) -> Result<EmailVerifierOutput, VerifyError> {
+    try_verify_email_with_version(email, CommitmentVersion::V1)
+}
+
+/// Non-panicking variant of [`verify_email_with_version`].
+pub fn try_verify_email_with_version(
+    email: &Email,
+    version: CommitmentVersion,
+) -> Result<EmailVerifierOutput, VerifyError> {
+    verify_email_output(email, version, false).map(|(output, _)| output)
+}
+
+/// Same as [`try_verify_email_with_version`], also returning the hash of the full `From`
+/// address computed by [`from_address_hash`], for applications that commit to the sender and
+/// not just its domain.
+///
+/// The email is rejected with [`VerifyError::FromNotSigned`] unless `From` is signed, and with
+/// [`VerifyError::FromDomainMismatch`] unless the `From` domain is `email.from_domain` or one of
+/// its subdomains, since the address would otherwise not be vouched for by the signer.
+pub fn try_verify_email_with_from_address(
+    email: &Email,
+    version: CommitmentVersion,
+) -> Result<(EmailVerifierOutput, Vec<u8>), VerifyError> {
+    let (output, _) = verify_email_output(email, version, true)?;
+
+    let invalid = |e: &dyn fmt::Display| VerifyError::InvalidInput(e.to_string());
+    let parsed_email = parse_mail(&email.raw_email).map_err(|e| invalid(&e))?;
+    let from_domain = extract_from_domain(&parsed_email).map_err(|e| invalid(&e))?;
+    let signing_domain = email.from_domain.to_lowercase();
+    if !is_within_domain(&from_domain, &signing_domain) {
+        return Err(VerifyError::FromDomainMismatch {
+            from_domain,
+            signing_domain,
+        });
+    }
+
+    let address_hash = from_address_hash(&email.raw_email, version).map_err(|e| invalid(&e))?;
+    Ok((output, address_hash))
+}
+
+/// Verifies an email and computes its committed output, also returning the raw email stripped of
+/// every DKIM-Signature header but the one that verified.
+fn verify_email_output(
+    email: &Email,
+    version: CommitmentVersion,
+    require_signed_from: bool,
+) -> Result<(EmailVerifierOutput, Vec<u8>), VerifyError> {
     let logger = Logger::root(Discard, o!());
 
-    let verified = verify_dkim(email, &logger);
-    assert!(verified);
-
-    EmailVerifierOutput {
-        from_domain_hash: hash_bytes(email.from_domain.as_bytes()),
-        public_key_hash: hash_bytes(&email.public_key.key),
-        external_inputs: email
-            .external_inputs
-            .iter()
-            .flat_map(|inputs| {
-                vec![
-                    inputs.name.clone(),
-                    inputs.value.clone().expect("Value cannot be null"),
-                ]
-            })
-            .collect(),
+    let (_, verified_email) = verify_dkim_signature_checked(email, &logger, require_signed_from)?;
+
+    let external_inputs = collect_external_inputs(&email.external_inputs)?;
+
+    let output = EmailVerifierOutput {
+        from_domain_hash: version.hash_domain(email.from_domain.as_bytes()),
+        public_key_hash: version.hash_email_public_key(&email.public_key),
+        external_inputs,
+    };
+    Ok((output, verified_email))
+}
+
+/// Flattens external inputs into the committed `[name1, value1, name2, value2, ...]` layout,
+/// rejecting required inputs without a value and values longer than their `max_length`.
+///
+/// Inputs are emitted in the order of `inputs` and are never sorted or deduplicated: that order
+/// is part of the committed output, and on-chain decoders rely on it.
+fn collect_external_inputs(inputs: &[ExternalInput]) -> Result<Vec<String>, VerifyError> {
+    let mut external_inputs = Vec::with_capacity(inputs.len() * 2);
+    for input in inputs {
+        let value = match (&input.value, input.required) {
+            (Some(value), _) => value.clone(),
+            (None, false) => String::new(),
+            (None, true) => return Err(VerifyError::MissingExternalInput(input.name.clone())),
+        };
+        if value.len() > input.max_length {
+            return Err(VerifyError::ExternalInputTooLong {
+                name: input.name.clone(),
+                length: value.len(),
+                max_length: input.max_length,
+            });
+        }
+        external_inputs.push(input.name.clone());
+        external_inputs.push(value);
     }
+    Ok(external_inputs)
 }
 
 pub fn verify_email_with_regex(input: &EmailWithRegex) -> EmailWithRegexVerifierOutput {
-    let email_verifier_output = verify_email(&input.email);
+    verify_email_with_regex_and_limit(input, DEFAULT_MAX_TOTAL_CAPTURES)
+}
 
-    let (canonicalized_header, canonicalized_body, _) =
-        canonicalize_signed_email(&input.email.raw_email).unwrap();
-
-    let (cleaned_body, _) = remove_quoted_printable_soft_breaks(canonicalized_body);
-
-    let header_matches = input
-        .regex_info
-        .header_parts
-        .as_ref()
-        .map(|parts| process_regex_parts(parts, &canonicalized_header))
-        .map(|(verified, matches)| {
-            assert!(verified);
-            matches
-        });
-    let body_matches = input
-        .regex_info
-        .body_parts
-        .as_ref()
-        .map(|parts| process_regex_parts(parts, &cleaned_body))
-        .map(|(verified, matches)| {
-            assert!(verified);
-            matches
-        });
+/// Same as [`verify_email_with_regex`], aborting when the header and body parts together
+/// produce more than `max_total_captures` captures, so the committed output stays bounded.
+///
+/// # Panics
+///
+/// Panics if [`try_verify_email_with_regex_and_limit`] returns an error.
+pub fn verify_email_with_regex_and_limit(
+    input: &EmailWithRegex,
+    max_total_captures: usize,
+) -> EmailWithRegexVerifierOutput {
+    try_verify_email_with_regex_and_limit(input, max_total_captures)
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Non-panicking variant of [`verify_email_with_regex`], reporting which regex part failed to
+/// match.
+pub fn try_verify_email_with_regex(
+    input: &EmailWithRegex,
+) -> Result<EmailWithRegexVerifierOutput, VerifyError> {
+    try_verify_email_with_regex_and_limit(input, DEFAULT_MAX_TOTAL_CAPTURES)
+}
+
+/// Non-panicking variant of [`verify_email_with_regex_and_limit`].
+pub fn try_verify_email_with_regex_and_limit(
+    input: &EmailWithRegex,
+    max_total_captures: usize,
+) -> Result<EmailWithRegexVerifierOutput, VerifyError> {
+    try_verify_email_with_regex_and_version(input, max_total_captures, CommitmentVersion::V1)
+}
+
+/// Same as [`try_verify_email_with_regex_and_limit`], computing the committed hashes as defined
+/// by `version`.
+pub fn try_verify_email_with_regex_and_version(
+    input: &EmailWithRegex,
+    max_total_captures: usize,
+    version: CommitmentVersion,
+) -> Result<EmailWithRegexVerifierOutput, VerifyError> {
+    // The regex parts are matched against the content of the signature that verified
+    let (email_verifier_output, verified_email) =
+        verify_email_output(&input.email, version, false)?;
+    let regex_matches = match_regex_info(&verified_email, &input.regex_info)?;
 
-    let regex_matches = header_matches
-        .into_iter()
-        .chain(body_matches)
-        .flatten()
-        .collect();
+    if regex_matches.len() > max_total_captures {
+        return Err(VerifyError::TooManyCaptures {
+            count: regex_matches.len(),
+            max: max_total_captures,
+        });
+    }
 
-    EmailWithRegexVerifierOutput {
+    Ok(EmailWithRegexVerifierOutput {
         email: email_verifier_output,
         regex_matches,
+    })
+}
+
+/// Runs the header and body regex parts of `regex_info` against a raw email and returns their
+/// captures, without verifying its DKIM signature.
+///
+/// **Insecure: never use this in production or in a guest.** Nothing binds the captures to a
+/// signed email, so anyone can forge them. It only exists to iterate on regex configs while no
+/// DKIM key is available; use [`verify_email_with_regex`] for anything that gets proven.
+pub fn verify_regex_only(raw_email: &[u8], regex_info: &RegexInfo) -> Result<Vec<String>> {
+    Ok(match_regex_info(raw_email, regex_info)?)
+}
+
+/// Returns the header and body that regex parts are matched against, in the forms selected by
+/// `header_input` and `match_target`, for the DKIM signature that verifies `email`.
+///
+/// Host-side tooling compiles regex parts against these inputs, so that they match what
+/// [`verify_email_with_regex`] sees.
+pub fn regex_match_inputs(
+    email: &Email,
+    header_input: HeaderInput,
+    match_target: MatchTarget,
+) -> Result<(Vec<u8>, Vec<u8>), VerifyError> {
+    let logger = Logger::root(Discard, o!());
+    let (_, verified_email) = verify_dkim_signature_checked(email, &logger, false)?;
+    match_inputs(&verified_email, header_input, match_target)
+}
+
+/// Same as [`regex_match_inputs`] for the first DKIM signature of a raw email, without verifying
+/// it.
+fn match_inputs(
+    raw_email: &[u8],
+    header_input: HeaderInput,
+    match_target: MatchTarget,
+) -> Result<(Vec<u8>, Vec<u8>), VerifyError> {
+    let invalid = |e: anyhow::Error| VerifyError::InvalidInput(e.to_string());
+    let (canonicalized_header, canonicalized_body, _) =
+        canonicalize_checked(raw_email).map_err(VerifyError::Canonicalization)?;
+
+    let body_input =
+        body_match_input(raw_email, canonicalized_body, match_target).map_err(invalid)?;
+    let header_input = match header_input {
+        HeaderInput::Canonicalized => canonicalized_header,
+        HeaderInput::Raw => signed_raw_header(raw_email).map_err(invalid)?,
+    };
+    Ok((header_input, body_input))
+}
+
+/// Matches the regex parts of `regex_info` against the header and body of a raw email, in the
+/// form they are committed in, and returns their captures in order.
+fn match_regex_info(raw_email: &[u8], regex_info: &RegexInfo) -> Result<Vec<String>, VerifyError> {
+    let (header_input, body_input) =
+        match_inputs(raw_email, regex_info.header_input, regex_info.match_target)?;
+
+    let mut regex_matches = Vec::new();
+    for (index, part) in regex_info.header_parts.iter().flatten().enumerate() {
+        let (verified, matches) = process_regex_parts(std::slice::from_ref(part), &header_input);
+        if !verified {
+            return Err(VerifyError::HeaderRegexMismatch(index));
+        }
+        regex_matches.extend(matches);
+    }
+    for (index, part) in regex_info.body_parts.iter().flatten().enumerate() {
+        let (verified, matches) = process_regex_parts(std::slice::from_ref(part), &body_input);
+        if !verified {
+            return Err(VerifyError::BodyRegexMismatch(index));
+        }
+        regex_matches.extend(matches);
+    }
+    Ok(regex_matches)
+}
+
+/// Verifies an email and returns the ABI-encoded `SolEmailOutput` to commit from the guest.
+pub fn verify_email_and_encode(email: &Email) -> Vec<u8> {
+    verify_email_and_encode_with_version(email, CommitmentVersion::V1)
+}
+
+/// Same as [`verify_email_and_encode`], computing the committed hashes as defined by `version`.
+pub fn verify_email_and_encode_with_version(email: &Email, version: CommitmentVersion) -> Vec<u8> {
+    VerificationOutput::EmailOnly(verify_email_with_version(email, version))
+        .abi_encode()
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Verifies an email with its regex parts and returns the ABI-encoded
+/// `SolEmailWithRegexOutput` to commit from the guest.
+///
+/// The regex matches are always included, so the commitment layout is the same whether or not
+/// any header or body parts were configured.
+pub fn verify_and_encode(input: &EmailWithRegex) -> Vec<u8> {
+    verify_and_encode_with_version(input, CommitmentVersion::V1)
+}
+
+/// Same as [`verify_and_encode`], computing the committed hashes as defined by `version`.
+///
+/// # Panics
+///
+/// Panics if [`try_verify_email_with_regex_and_version`] returns an error.
+pub fn verify_and_encode_with_version(
+    input: &EmailWithRegex,
+    version: CommitmentVersion,
+) -> Vec<u8> {
+    let output =
+        try_verify_email_with_regex_and_version(input, DEFAULT_MAX_TOTAL_CAPTURES, version)
+            .unwrap_or_else(|e| panic!("{}", e));
+    VerificationOutput::WithRegex {
+        email: output.email,
+        matches: output.regex_matches,
+    }
+    .abi_encode()
+    .unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Verifies an email and summarizes the result as a [`VerificationReport`].
+///
+/// Unlike [`verify_email`] this does not panic on a failed verification; the signature and body
+/// hash outcomes are reported separately, for the first signature whose header signature
+/// verifies, or for the first signature if none does. A public key that cannot be loaded is
+/// reported as a `permerror`. Errors are only returned for emails that cannot be parsed or
+/// carry no DKIM signature.
+pub fn verify_report(email: &Email) -> Result<VerificationReport> {
+    verify_report_with_version(email, CommitmentVersion::V1)
+}
+
+/// Same as [`verify_report`], reporting the hashes as defined by `version`, so that they match
+/// the output committed by [`verify_email_with_version`].
+pub fn verify_report_with_version(
+    email: &Email,
+    version: CommitmentVersion,
+) -> Result<VerificationReport> {
+    let logger = Logger::root(Discard, o!());
+    let signatures = extract_all_dkim_fields(&email.raw_email)?;
+    if signatures.is_empty() {
+        return Err(anyhow!("No DKIM signatures found"));
+    }
+
+    // The header signature is verified once, ignoring the body hash, which is checked here so
+    // that both outcomes describe the same signature
+    let (signature_detail, verified_email) =
+        match DkimPublicKey::try_from_bytes(&email.public_key.key, &email.public_key.key_type) {
+            Ok(_) => {
+                let candidates: Vec<usize> = (0..signatures.len()).collect();
+                verify_dkim_signatures(
+                    &email.raw_email,
+                    &email.from_domain,
+                    &email.public_key,
+                    &logger,
+                    true,
+                    &candidates,
+                )?
+            }
+            Err(e) => (format!("permerror (invalid public key: {})", e), None),
+        };
+    let signature_valid = verified_email.is_some();
+    let reported_email = verified_email
+        .unwrap_or_else(|| retain_dkim_signatures(&email.raw_email, |index| index == 0));
+
+    let fields = extract_dkim_fields(&reported_email)?;
+    let tag = |name: &str| fields.get(name).cloned().unwrap_or_default();
+    let body_hash_valid = verify_body_hash_only(&reported_email)?;
+    let dkim_detail = if signature_valid && !body_hash_valid {
+        "fail (body hash did not verify)".to_string()
+    } else {
+        signature_detail
+    };
+
+    Ok(VerificationReport {
+        domain: tag("d"),
+        selector: tag("s"),
+        algorithm: tag("a"),
+        signature_valid,
+        body_hash_valid,
+        dkim_detail,
+        from_domain_hash: to_hex(&version.hash_domain(email.from_domain.as_bytes())),
+        public_key_hash: to_hex(&version.hash_email_public_key(&email.public_key)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use rsa::{
+        pkcs1::{DecodeRsaPrivateKey, EncodeRsaPublicKey},
+        RsaPrivateKey,
+    };
+
+    use super::*;
+    use crate::{
+        compile_test_dfa, hash_bytes, sign_dkim, CompiledRegex, PublicKey, TEST_PRIVATE_KEY,
+    };
+
+    /// The Ed25519-signed example of RFC 8463, appendix A.3, with only its Ed25519 signature.
+    const ED25519_EMAIL: &[u8] = b"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n\
+\x20d=football.example.com; i=@football.example.com;\r\n\
+\x20q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n\
+\x20subject : date : message-id : from : subject : date;\r\n\
+\x20bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n\
+\x20b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n\
+\x20Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n\
+From: Joe SixPack <joe@football.example.com>\r\n\
+To: Suzie Q <suzie@shopping.example.net>\r\n\
+Subject: Is dinner ready?\r\n\
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n\
+Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+\r\n\
+Hi.\r\n\
+\r\n\
+We lost the game.  Are you hungry yet?\r\n\
+\r\n\
+Joe.";
+
+    const ED25519_PUBLIC_KEY: &str = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+    fn external_input(value: &str, max_length: usize) -> ExternalInput {
+        ExternalInput {
+            name: "handle".to_string(),
+            value: Some(value.to_string()),
+            max_length,
+            required: true,
+        }
+    }
+
+    #[test]
+    fn test_collect_external_inputs_enforces_max_length() {
+        assert_eq!(
+            collect_external_inputs(&[external_input("alice", 5)]),
+            Ok(vec!["handle".to_string(), "alice".to_string()])
+        );
+        assert_eq!(
+            collect_external_inputs(&[external_input("alice!", 5)]),
+            Err(VerifyError::ExternalInputTooLong {
+                name: "handle".to_string(),
+                length: 6,
+                max_length: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn test_collect_external_inputs_without_value() {
+        let optional = ExternalInput {
+            value: None,
+            required: false,
+            ..external_input("", 5)
+        };
+        assert_eq!(
+            collect_external_inputs(&[optional]),
+            Ok(vec!["handle".to_string(), String::new()])
+        );
+
+        let required = ExternalInput {
+            value: None,
+            ..external_input("", 5)
+        };
+        assert_eq!(
+            collect_external_inputs(&[required]),
+            Err(VerifyError::MissingExternalInput("handle".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_collect_external_inputs_preserves_order() {
+        let inputs: Vec<ExternalInput> = ["zeta", "alpha", "mid"]
+            .into_iter()
+            .map(|name| ExternalInput {
+                name: name.to_string(),
+                ..external_input(&format!("{}-value", name), 32)
+            })
+            .collect();
+
+        assert_eq!(
+            collect_external_inputs(&inputs).unwrap(),
+            vec![
+                "zeta",
+                "zeta-value",
+                "alpha",
+                "alpha-value",
+                "mid",
+                "mid-value"
+            ]
+        );
+    }
+
+    fn ed25519_email() -> Email {
+        Email {
+            from_domain: "football.example.com".to_string(),
+            raw_email: ED25519_EMAIL.to_vec(),
+            public_key: PublicKey {
+                key: STANDARD.decode(ED25519_PUBLIC_KEY).unwrap(),
+                key_type: "ed25519".to_string(),
+            },
+            external_inputs: Vec::new(),
+        }
+    }
+
+    fn regex_part(pattern: &str, capture: &str) -> CompiledRegex {
+        CompiledRegex {
+            verify_re: compile_test_dfa(pattern),
+            captures: Some(vec![capture.to_string()]),
+            must_not_match: false,
+            header_name: None,
+            compressed: false,
+        }
+    }
+
+    #[test]
+    fn test_verify_email_ed25519() {
+        let email = ed25519_email();
+        let key = email.public_key.key.clone();
+
+        let output = try_verify_email(&email).unwrap();
+        assert_eq!(output.from_domain_hash, hash_bytes(b"football.example.com"));
+        assert_eq!(output.from_domain_hash.len(), 32);
+        assert_eq!(output.public_key_hash, hash_bytes(&key));
+
+        let mut tampered = email.clone();
+        tampered.raw_email = String::from_utf8_lossy(ED25519_EMAIL)
+            .replace("hungry", "thirsty")
+            .into_bytes();
+        assert!(matches!(
+            try_verify_email(&tampered),
+            Err(VerifyError::DkimFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_report() {
+        let report = verify_report(&ed25519_email()).unwrap();
+        assert_eq!(report.domain, "football.example.com");
+        assert_eq!(report.algorithm, "ed25519-sha256");
+        assert!(report.signature_valid && report.body_hash_valid);
+        assert_eq!(report.dkim_detail, "pass");
+
+        let mut tampered = ed25519_email();
+        tampered.raw_email = String::from_utf8_lossy(ED25519_EMAIL)
+            .replace("hungry", "thirsty")
+            .into_bytes();
+        let report = verify_report(&tampered).unwrap();
+        assert!(report.signature_valid && !report.body_hash_valid);
+        assert_eq!(report.dkim_detail, "fail (body hash did not verify)");
+
+        let mut bad_key = ed25519_email();
+        bad_key.public_key.key = vec![1, 2, 3];
+        let report = verify_report(&bad_key).unwrap();
+        assert!(!report.signature_valid && report.body_hash_valid);
+        assert!(report.dkim_detail.starts_with("permerror"));
+    }
+
+    #[test]
+    fn test_verify_report_with_version() {
+        for version in [CommitmentVersion::V1, CommitmentVersion::V2] {
+            let output = try_verify_email_with_version(&ed25519_email(), version).unwrap();
+            let report = verify_report_with_version(&ed25519_email(), version).unwrap();
+            assert_eq!(report.from_domain_hash, to_hex(&output.from_domain_hash));
+            assert_eq!(report.public_key_hash, to_hex(&output.public_key_hash));
+        }
+    }
+
+    #[test]
+    fn test_try_verify_email_with_from_address() {
+        let (output, address_hash) =
+            try_verify_email_with_from_address(&ed25519_email(), CommitmentVersion::V1).unwrap();
+        assert_eq!(output, try_verify_email(&ed25519_email()).unwrap());
+        assert_eq!(address_hash, hash_bytes(b"joe@football.example.com"));
+    }
+
+    #[test]
+    fn test_from_address_rejects_prepended_from() {
+        let private_key = RsaPrivateKey::from_pkcs1_pem(TEST_PRIVATE_KEY).unwrap();
+        let mut email = Email {
+            from_domain: "example.com".to_string(),
+            raw_email: sign_dkim(
+                b"From: alice@example.com\r\nSubject: Hi\r\n\r\nHi\r\n",
+                "v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1; h=from:subject",
+                &private_key,
+            )
+            .unwrap(),
+            public_key: PublicKey {
+                key: private_key
+                    .to_public_key()
+                    .to_pkcs1_der()
+                    .unwrap()
+                    .as_bytes()
+                    .to_vec(),
+                key_type: "rsa".to_string(),
+            },
+            external_inputs: Vec::new(),
+        };
+        assert!(try_verify_email_with_from_address(&email, CommitmentVersion::V1).is_ok());
+
+        // DKIM selects the bottom-most From, so a prepended one leaves the signature valid
+        email.raw_email = [b"From: ceo@victim.example\r\n".as_slice(), &email.raw_email].concat();
+        assert!(try_verify_email(&email).is_ok());
+        assert!(matches!(
+            try_verify_email_with_from_address(&email, CommitmentVersion::V1),
+            Err(VerifyError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_address_rejects_unaligned_from_domain() {
+        let private_key = RsaPrivateKey::from_pkcs1_pem(TEST_PRIVATE_KEY).unwrap();
+        let email = |from: &str| Email {
+            from_domain: "attacker.example".to_string(),
+            raw_email: sign_dkim(
+                format!("From: {}\r\nSubject: Hi\r\n\r\nHi\r\n", from).as_bytes(),
+                "v=1; a=rsa-sha256; c=relaxed/relaxed; d=attacker.example; s=s1; h=from:subject",
+                &private_key,
+            )
+            .unwrap(),
+            public_key: PublicKey {
+                key: private_key
+                    .to_public_key()
+                    .to_pkcs1_der()
+                    .unwrap()
+                    .as_bytes()
+                    .to_vec(),
+                key_type: "rsa".to_string(),
+            },
+            external_inputs: Vec::new(),
+        };
+
+        // The signature is valid, but attacker.example cannot vouch for a bank.example sender
+        let forged = email("ceo@bank.example");
+        assert!(try_verify_email(&forged).is_ok());
+        assert_eq!(
+            try_verify_email_with_from_address(&forged, CommitmentVersion::V1),
+            Err(VerifyError::FromDomainMismatch {
+                from_domain: "bank.example".to_string(),
+                signing_domain: "attacker.example".to_string(),
+            })
+        );
+
+        assert!(try_verify_email_with_from_address(
+            &email("alice@mail.attacker.example"),
+            CommitmentVersion::V1
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_try_verify_email_with_regex() {
+        let input = |body_pattern: &str| EmailWithRegex {
+            email: ed25519_email(),
+            regex_info: RegexInfo {
+                header_parts: Some(vec![regex_part("to:[A-Za-z]+ Q <", "Suzie")]),
+                body_parts: Some(vec![
+                    regex_part("We lost the [a-z]+", "game"),
+                    regex_part(body_pattern, "hungry"),
+                ]),
+                header_input: HeaderInput::Canonicalized,
+                match_target: MatchTarget::SignedWire,
+            },
+        };
+
+        let output = try_verify_email_with_regex(&input("Are you [a-z]+ yet")).unwrap();
+        assert_eq!(output.regex_matches, ["Suzie", "game", "hungry"]);
+        assert_eq!(
+            try_verify_email_with_regex_and_limit(&input("Are you [a-z]+ yet"), 2),
+            Err(VerifyError::TooManyCaptures { count: 3, max: 2 })
+        );
+        assert_eq!(
+            try_verify_email_with_regex(&input("Are you [a-z]+ now")),
+            Err(VerifyError::BodyRegexMismatch(1))
+        );
+
+        let output = try_verify_email_with_regex_and_version(
+            &input("Are you [a-z]+ yet"),
+            DEFAULT_MAX_TOTAL_CAPTURES,
+            CommitmentVersion::V2,
+        )
+        .unwrap();
+        assert_eq!(
+            output.email,
+            try_verify_email_with_version(&ed25519_email(), CommitmentVersion::V2).unwrap()
+        );
+        assert_eq!(output.regex_matches, ["Suzie", "game", "hungry"]);
+    }
+
+    #[test]
+    fn test_raw_header_input_excludes_unsigned_fields() {
+        let input = |raw_email: Vec<u8>, pattern: &str| EmailWithRegex {
+            email: Email {
+                raw_email,
+                ..ed25519_email()
+            },
+            regex_info: RegexInfo {
+                header_parts: Some(vec![regex_part(pattern, "dinner")]),
+                body_parts: None,
+                header_input: HeaderInput::Raw,
+                match_target: MatchTarget::SignedWire,
+            },
+        };
+
+        // Header names keep their case, unlike in the canonicalized header
+        assert!(try_verify_email_with_regex(&input(
+            ED25519_EMAIL.to_vec(),
+            "Subject: Is [a-z]+ ready"
+        ))
+        .is_ok());
+
+        // An unsigned field leaves the signature valid but is not matched against
+        let injected = [b"X-Note: Is lunch ready\r\n".as_slice(), ED25519_EMAIL].concat();
+        assert_eq!(
+            try_verify_email_with_regex(&input(injected, "X-Note: Is [a-z]+ ready")),
+            Err(VerifyError::HeaderRegexMismatch(0))
+        );
+    }
+
+    #[test]
+    fn test_verify_regex_only_skips_dkim() {
+        let regex_info = RegexInfo {
+            header_parts: None,
+            body_parts: Some(vec![regex_part("Are you [a-z]+ yet", "thirsty")]),
+            header_input: HeaderInput::Canonicalized,
+            match_target: MatchTarget::SignedWire,
+        };
+        let tampered = String::from_utf8_lossy(ED25519_EMAIL).replace("hungry", "thirsty");
+
+        assert_eq!(
+            verify_regex_only(tampered.as_bytes(), &regex_info).unwrap(),
+            ["thirsty"]
+        );
+        assert!(verify_regex_only(ED25519_EMAIL, &regex_info).is_err());
     }
 }
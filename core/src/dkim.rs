@@ -0,0 +1,1708 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use cfdkim::canonicalize_signed_email;
+use mailparse::{dateparse, parse_content_type, parse_headers, MailHeader, MailHeaderMap};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+use crate::{
+    canonicalize_body_relaxed, canonicalize_body_simple, compute_body_hash, normalize_line_endings,
+    raw_header, sign_rsa, verify_body_with_hash, verify_rsa, DkimOptions, HashAlgorithm,
+    VerificationResult,
+};
+
+/// The pieces of a DKIM signature of an email needed to verify it without a DNS lookup.
+#[derive(Debug, Clone)]
+pub struct DkimComponents {
+    /// The DKIM-Signature tags, keyed by tag name (`v`, `a`, `d`, `s`, `h`, `bh`, `b`, ...).
+    pub fields: HashMap<String, String>,
+    /// The signed headers, canonicalized as declared by the signature's `c=` tag.
+    pub canonicalized_header: Vec<u8>,
+    /// The body, canonicalized as declared by the signature's `c=` tag.
+    pub canonicalized_body: Vec<u8>,
+    /// The decoded `b=` signature.
+    pub signature: Vec<u8>,
+}
+
+/// The tags of a DKIM-Signature needed to recompute and check its body hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimHeaderSummary {
+    /// The base64 `bh=` body hash, with folding whitespace removed.
+    pub body_hash: String,
+    /// The `a=` signing algorithm, e.g. `rsa-sha256`.
+    pub algorithm: String,
+    /// The header canonicalization from `c=`, `simple` when absent.
+    pub header_canon: String,
+    /// The body canonicalization from `c=`, `simple` when absent.
+    pub body_canon: String,
+    /// The `s=` selector.
+    pub selector: String,
+    /// The `d=` signing domain.
+    pub domain: String,
+}
+
+/// The tags of a DKIM-Signature header, sorted into present, missing and unknown ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DkimTagAudit {
+    /// Every tag of the signature with its value, keyed by tag name.
+    pub present: BTreeMap<String, String>,
+    /// Tags RFC 6376 requires that the signature lacks, in [`REQUIRED_DKIM_TAGS`] order.
+    pub missing: Vec<&'static str>,
+    /// Tags of the signature that RFC 6376 does not define, sorted by name.
+    pub unknown: Vec<String>,
+}
+
+impl DkimTagAudit {
+    /// Returns `true` if no required tag is missing.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// How the DKIM-covered content of a possibly modified copy of an email differs from the
+/// original, as returned by [`diff_signed_content`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedDiff {
+    /// `true` if any header selected by the original signature's `h=` tag differs after
+    /// canonicalization, or is missing from one of the emails.
+    pub headers_differ: bool,
+    /// `true` if the hash of the signed portion of the body differs.
+    pub body_hash_differs: bool,
+    /// The name of the first signed header, in `h=` order, that differs.
+    pub first_divergent_header: Option<String>,
+}
+
+impl SignedDiff {
+    /// Returns `true` if neither the signed headers nor the signed body changed.
+    pub fn is_unchanged(&self) -> bool {
+        !self.headers_differ && !self.body_hash_differs
+    }
+}
+
+/// The header canonicalization algorithm of a DKIM signature (RFC 6376 section 3.4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderCanon {
+    /// Header fields are hashed exactly as they appear.
+    #[default]
+    Simple,
+    /// Header names are lowercased, values unfolded and whitespace runs collapsed.
+    Relaxed,
+}
+
+/// The body canonicalization algorithm of a DKIM signature (RFC 6376 section 3.4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BodyCanon {
+    /// Only trailing empty lines are removed.
+    #[default]
+    Simple,
+    /// Trailing whitespace is removed from each line and whitespace runs are collapsed as well.
+    Relaxed,
+}
+
+/// The ARC headers of one hop of an ARC chain, sharing the same `i=` instance number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArcSet {
+    /// The `i=` instance number, starting at 1 for the first hop.
+    pub instance: u32,
+    /// The tags of the `ARC-Seal` header, if present.
+    pub seal: Option<HashMap<String, String>>,
+    /// The tags of the `ARC-Message-Signature` header, if present.
+    pub message_signature: Option<HashMap<String, String>>,
+    /// The raw value of the `ARC-Authentication-Results` header, if present.
+    pub authentication_results: Option<String>,
+    /// `true` when the preceding instance number is missing from the chain, i.e. this is the
+    /// first set but its instance is not 1, or the previous set's instance is not `instance - 1`.
+    pub gap_before: bool,
+}
+
+impl ArcSet {
+    /// Returns `true` if the set has all three ARC headers.
+    pub fn is_complete(&self) -> bool {
+        self.seal.is_some()
+            && self.message_signature.is_some()
+            && self.authentication_results.is_some()
+    }
+}
+
+/// Error returned when the `t=` or `x=` tags put a DKIM signature outside its validity window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureTimeError {
+    /// The `x=` expiration timestamp is in the past. `overshoot` is how long ago it passed,
+    /// beyond the allowed clock skew.
+    Expired {
+        expiration: u64,
+        overshoot: Duration,
+    },
+    /// The `t=` signing timestamp is in the future. `overshoot` is how far ahead it is, beyond
+    /// the allowed clock skew.
+    FutureTimestamp { timestamp: u64, overshoot: Duration },
+    /// The named tag is not a valid Unix timestamp.
+    InvalidTag(&'static str),
+}
+
+impl fmt::Display for SignatureTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired {
+                expiration,
+                overshoot,
+            } => write!(
+                f,
+                "signature expired at {} ({}s beyond the allowed skew)",
+                expiration,
+                overshoot.as_secs()
+            ),
+            Self::FutureTimestamp {
+                timestamp,
+                overshoot,
+            } => write!(
+                f,
+                "signature timestamp {} is in the future ({}s beyond the allowed skew)",
+                timestamp,
+                overshoot.as_secs()
+            ),
+            Self::InvalidTag(tag) => write!(f, "invalid {}= tag", tag),
+        }
+    }
+}
+
+impl std::error::Error for SignatureTimeError {}
+
+/// Error returned when the `i=` tag of a DKIM signature is outside the `d=` signing domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuidError {
+    /// The signature has no `d=` tag to scope the `i=` tag to.
+    MissingDomain,
+    /// The `i=` tag is not of the form `[local-part]@domain`.
+    Malformed(String),
+    /// The domain of the `i=` tag is neither the `d=` domain nor one of its subdomains.
+    OutOfScope { auid_domain: String, domain: String },
+}
+
+impl fmt::Display for AuidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingDomain => write!(f, "DKIM-Signature is missing the d= tag"),
+            Self::Malformed(auid) => write!(f, "invalid i= tag: {}", auid),
+            Self::OutOfScope {
+                auid_domain,
+                domain,
+            } => write!(
+                f,
+                "i= domain {} is not within the d= domain {}",
+                auid_domain, domain
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuidError {}
+
+/// Why [`canonicalize_checked`] could not canonicalize an email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanonError {
+    /// The header block could not be parsed.
+    MalformedHeaders(String),
+    /// The email has no DKIM-Signature header.
+    MissingSignature,
+    /// The first DKIM-Signature lacks these required tags.
+    MissingTags(Vec<&'static str>),
+    /// The `c=` tag names an unknown header or body canonicalization algorithm.
+    UnsupportedCanonicalization(String),
+    /// The `b=` tag is not valid base64.
+    InvalidSignatureEncoding(String),
+    /// cfdkim rejected the email for another reason; carries its message.
+    Other(String),
+}
+
+impl fmt::Display for CanonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedHeaders(e) => write!(f, "Malformed email headers: {}", e),
+            Self::MissingSignature => write!(f, "No DKIM signatures found"),
+            Self::MissingTags(tags) => write!(
+                f,
+                "DKIM-Signature is missing the required tags: {}",
+                tags.join(", ")
+            ),
+            Self::UnsupportedCanonicalization(e) => write!(f, "{}", e),
+            Self::InvalidSignatureEncoding(e) => {
+                write!(f, "DKIM-Signature b= tag is not valid base64: {}", e)
+            }
+            Self::Other(e) => write!(f, "Failed to canonicalize email: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CanonError {}
+
+/// The tags every DKIM-Signature must carry (RFC 6376 section 3.5).
+pub const REQUIRED_DKIM_TAGS: [&str; 7] = ["v", "a", "b", "bh", "d", "h", "s"];
+
+/// The optional tags defined by RFC 6376 section 3.5.
+const OPTIONAL_DKIM_TAGS: [&str; 7] = ["c", "i", "l", "q", "t", "x", "z"];
+
+/// Parses a DKIM-Signature header value into a map of tag names to values.
+///
+/// Tag names and values are trimmed; whitespace inside values (e.g. folded `b=`) is preserved.
+pub fn parse_dkim_tags(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Summarizes a DKIM-Signature header, given either its value or the full `DKIM-Signature: ...`
+/// line.
+pub fn extract_dkim_header_summary(header: &[u8]) -> Result<DkimHeaderSummary> {
+    let fields = parse_dkim_header(header);
+
+    let required = |tag: &str| {
+        fields
+            .get(tag)
+            .cloned()
+            .ok_or_else(|| anyhow!("DKIM-Signature is missing the {}= tag", tag))
+    };
+
+    let (header_canon, body_canon) = canonicalization_names(&fields);
+
+    Ok(DkimHeaderSummary {
+        body_hash: required("bh")?.split_whitespace().collect(),
+        algorithm: required("a")?,
+        header_canon: header_canon.to_string(),
+        body_canon: body_canon.to_string(),
+        selector: required("s")?,
+        domain: required("d")?,
+    })
+}
+
+/// Lists the tags of a DKIM-Signature header, given either its value or the full
+/// `DKIM-Signature: ...` line, along with the required tags it lacks and the tags RFC 6376 does
+/// not define.
+///
+/// Unlike verification, this never fails, so it can explain why a signature was rejected.
+pub fn dkim_tag_audit(header: &[u8]) -> DkimTagAudit {
+    let present: BTreeMap<String, String> = parse_dkim_header(header).into_iter().collect();
+    let missing = REQUIRED_DKIM_TAGS
+        .into_iter()
+        .filter(|tag| !present.contains_key(*tag))
+        .collect();
+    let unknown = present
+        .keys()
+        .filter(|tag| {
+            !REQUIRED_DKIM_TAGS.contains(&tag.as_str())
+                && !OPTIONAL_DKIM_TAGS.contains(&tag.as_str())
+        })
+        .cloned()
+        .collect();
+
+    DkimTagAudit {
+        present,
+        missing,
+        unknown,
+    }
+}
+
+/// Parses the tags of a DKIM-Signature header, given either its value or the full line.
+fn parse_dkim_header(header: &[u8]) -> HashMap<String, String> {
+    let header = String::from_utf8_lossy(header);
+    let value = match header.split_once(':') {
+        Some((name, value)) if name.trim().eq_ignore_ascii_case("DKIM-Signature") => value,
+        _ => header.as_ref(),
+    };
+    parse_dkim_tags(value)
+}
+
+/// Extracts the tags of the first DKIM-Signature header of a raw email.
+pub fn extract_dkim_fields(raw_email: &[u8]) -> Result<HashMap<String, String>> {
+    let (headers, _) = parse_headers(raw_email)?;
+    first_dkim_fields(&headers)
+}
+
+/// Extracts the tags of every DKIM-Signature header of a raw email, in header order.
+pub fn extract_all_dkim_fields(raw_email: &[u8]) -> Result<Vec<HashMap<String, String>>> {
+    let (headers, _) = parse_headers(raw_email)?;
+    Ok(headers
+        .get_all_headers("DKIM-Signature")
+        .into_iter()
+        .map(|header| parse_dkim_tags(&String::from_utf8_lossy(header.get_value_raw())))
+        .collect())
+}
+
+/// Returns a copy of a raw email without the DKIM-Signature headers whose index, in header
+/// order, is rejected by `keep`. All other bytes, including line endings, are left untouched.
+///
+/// Keeping a single signature makes verifiers that stop at the first passing signature, and
+/// functions that read the first signature, operate on that signature.
+pub(crate) fn retain_dkim_signatures<F>(raw_email: &[u8], keep: F) -> Vec<u8>
+where
+    F: Fn(usize) -> bool,
+{
+    let header = raw_header(raw_email);
+    let mut retained = Vec::with_capacity(raw_email.len());
+    let mut signatures = 0;
+    let mut keep_field = true;
+    for line in header.split_inclusive(|&byte| byte == b'\n') {
+        // Folded lines belong to the field they continue
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let name = line.split(|&byte| byte == b':').next().unwrap_or_default();
+            keep_field = true;
+            if String::from_utf8_lossy(name)
+                .trim()
+                .eq_ignore_ascii_case("DKIM-Signature")
+            {
+                keep_field = keep(signatures);
+                signatures += 1;
+            }
+        }
+        if keep_field {
+            retained.extend_from_slice(line);
+        }
+    }
+    retained.extend_from_slice(&raw_email[header.len()..]);
+    retained
+}
+
+/// Counts the DKIM-Signature headers of a raw email without canonicalizing or verifying them,
+/// e.g. to filter a large corpus cheaply. An email whose header block cannot be parsed has none.
+pub fn count_dkim_signatures(eml_content: &[u8]) -> usize {
+    parse_headers(eml_content).map_or(0, |(headers, _)| {
+        headers.get_all_headers("DKIM-Signature").len()
+    })
+}
+
+/// Returns the `Date` header as a Unix timestamp.
+///
+/// Returns `None` when `Date` is not listed in the `h=` tag of the first DKIM signature, since an
+/// unsigned date cannot be trusted.
+pub fn extract_signed_date_unix(raw_email: &[u8]) -> Result<Option<i64>> {
+    let (headers, _) = parse_headers(raw_email)?;
+    let fields = first_dkim_fields(&headers)?;
+    if !requires_header(&fields, "Date") {
+        return Ok(None);
+    }
+
+    let date = headers
+        .get_first_value("Date")
+        .ok_or_else(|| anyhow!("No Date header found"))?;
+    dateparse(&date)
+        .map(Some)
+        .map_err(|e| anyhow!("Invalid Date header {:?}: {}", date, e))
+}
+
+/// Checks that the `Content-Type` header is covered by the first DKIM signature and declares the
+/// `expected` MIME type, so the body cannot be reinterpreted by altering its type.
+///
+/// A missing `Content-Type` header is treated as `text/plain`, per RFC 2045.
+pub fn verify_body_content_type(raw_email: &[u8], expected: &str) -> Result<bool> {
+    let (headers, _) = parse_headers(raw_email)?;
+    let fields = first_dkim_fields(&headers)?;
+    if !requires_header(&fields, "Content-Type") {
+        return Ok(false);
+    }
+
+    let mimetype = headers.get_first_value("Content-Type").map_or_else(
+        || "text/plain".to_string(),
+        |value| parse_content_type(&value).mimetype,
+    );
+    Ok(mimetype.eq_ignore_ascii_case(expected.trim()))
+}
+
+/// Groups the `ARC-Seal`, `ARC-Message-Signature` and `ARC-Authentication-Results` headers of
+/// a raw email by their `i=` instance number, in ascending instance order.
+///
+/// Headers may appear in any order. Missing instances and sets lacking a header are reported
+/// through [`ArcSet::gap_before`] and [`ArcSet::is_complete`] rather than rejected, so callers
+/// can apply their own policy. The chain is not verified.
+///
+/// # Errors
+///
+/// Returns an error if an ARC header has no valid `i=` tag or the same header appears twice for
+/// one instance.
+pub fn extract_arc_sets(eml_content: &[u8]) -> Result<Vec<ArcSet>> {
+    let (headers, _) = parse_headers(eml_content)?;
+    let mut sets: BTreeMap<u32, ArcSet> = BTreeMap::new();
+
+    for header in &headers {
+        let key = header.get_key();
+        let is_key = |name: &str| key.eq_ignore_ascii_case(name);
+        if !is_key("ARC-Seal")
+            && !is_key("ARC-Message-Signature")
+            && !is_key("ARC-Authentication-Results")
+        {
+            continue;
+        }
+        let value = String::from_utf8_lossy(header.get_value_raw()).into_owned();
+        let tags = parse_dkim_tags(&value);
+
+        let instance = tags
+            .get("i")
+            .and_then(|i| i.parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("{} header has no valid i= tag", key))?;
+        let set = sets.entry(instance).or_insert_with(|| ArcSet {
+            instance,
+            seal: None,
+            message_signature: None,
+            authentication_results: None,
+            gap_before: false,
+        });
+
+        let duplicate = if is_key("ARC-Seal") {
+            set.seal.replace(tags).is_some()
+        } else if is_key("ARC-Message-Signature") {
+            set.message_signature.replace(tags).is_some()
+        } else {
+            set.authentication_results
+                .replace(value.trim().to_string())
+                .is_some()
+        };
+        if duplicate {
+            return Err(anyhow!("Duplicate {} header for i={}", key, instance));
+        }
+    }
+
+    let mut previous = 0;
+    Ok(sets
+        .into_values()
+        .map(|mut set| {
+            set.gap_before = set.instance != previous + 1;
+            previous = set.instance;
+            set
+        })
+        .collect())
+}
+
+fn first_dkim_fields(headers: &[MailHeader]) -> Result<HashMap<String, String>> {
+    let header = headers
+        .get_first_header("DKIM-Signature")
+        .ok_or_else(|| anyhow!("No DKIM signatures found"))?;
+    Ok(parse_dkim_tags(&String::from_utf8_lossy(
+        header.get_value_raw(),
+    )))
+}
+
+/// Returns the lowercased names listed in the `h=` tag, in signing order. A header signed more
+/// than once appears once per occurrence.
+pub fn signed_headers(dkim_fields: &HashMap<String, String>) -> Vec<String> {
+    dkim_fields.get("h").map_or_else(Vec::new, |signed| {
+        signed
+            .split(':')
+            .map(|header| header.trim().to_lowercase())
+            .filter(|header| !header.is_empty())
+            .collect()
+    })
+}
+
+/// Returns `true` if the header `name` is covered by the signature's `h=` tag, ignoring case.
+///
+/// Headers missing from `h=` can be added or altered without breaking the signature, so callers
+/// should check this before trusting headers such as `From` or `Subject`.
+pub fn requires_header(dkim_fields: &HashMap<String, String>, name: &str) -> bool {
+    signed_headers(dkim_fields)
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name.trim()))
+}
+
+/// Canonicalizes a raw email and extracts the components of its first DKIM signature.
+///
+/// # Arguments
+///
+/// * `raw_email` - The raw email bytes.
+/// * `validity_time` - When set, the signature's `t=` and `x=` tags are checked against this
+///   time with [`check_signature_validity`].
+pub fn parse_dkim_components(
+    raw_email: &[u8],
+    validity_time: Option<SystemTime>,
+) -> Result<DkimComponents> {
+    let fields = extract_dkim_fields(raw_email)?;
+    if let Some(now) = validity_time {
+        check_signature_validity(&fields, now)?;
+    }
+
+    let (canonicalized_header, canonicalized_body, signature) = canonicalize_checked(raw_email)?;
+
+    Ok(DkimComponents {
+        fields,
+        canonicalized_header,
+        canonicalized_body,
+        signature,
+    })
+}
+
+/// Canonicalizes a raw email for its first DKIM signature, returning the canonicalized header,
+/// the canonicalized body and the decoded signature.
+///
+/// This wraps cfdkim's `canonicalize_signed_email`, checking its common failure modes first so
+/// they surface as typed [`CanonError`]s instead of an opaque message.
+pub fn canonicalize_checked(eml: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), CanonError> {
+    let (headers, _) =
+        parse_headers(eml).map_err(|e| CanonError::MalformedHeaders(e.to_string()))?;
+    let fields = first_dkim_fields(&headers).map_err(|_| CanonError::MissingSignature)?;
+
+    let missing: Vec<&'static str> = REQUIRED_DKIM_TAGS
+        .into_iter()
+        .filter(|tag| !fields.contains_key(*tag))
+        .collect();
+    if !missing.is_empty() {
+        return Err(CanonError::MissingTags(missing));
+    }
+    canonicalization_modes(&fields)
+        .map_err(|e| CanonError::UnsupportedCanonicalization(e.to_string()))?;
+    STANDARD
+        .decode(fields["b"].split_whitespace().collect::<String>())
+        .map_err(|e| CanonError::InvalidSignatureEncoding(e.to_string()))?;
+
+    canonicalize_signed_email(eml).map_err(|e| CanonError::Other(e.to_string()))
+}
+
+/// Canonicalizes a raw email once for each of its DKIM-Signature headers, in header order, so
+/// every signature can be verified on its own.
+///
+/// Each signature is parsed independently: one with an unknown `c=` algorithm or an undecodable
+/// `b=` tag yields an error in its slot without affecting the others. An email without
+/// signatures yields an empty list.
+pub fn parse_all_dkim_components(raw_email: &[u8]) -> Vec<Result<DkimComponents>> {
+    parse_all_dkim_components_with_options(raw_email, DkimOptions::default())
+}
+
+/// Same as [`parse_all_dkim_components`], applying `options` to the raw email first.
+pub fn parse_all_dkim_components_with_options(
+    raw_email: &[u8],
+    options: DkimOptions,
+) -> Vec<Result<DkimComponents>> {
+    all_signatures(&apply_options(raw_email, options))
+        .into_iter()
+        .map(|(_, components)| components)
+        .collect()
+}
+
+/// Returns the header fields signed by the first DKIM signature of a raw email, canonicalized as
+/// declared by its `c=` tag, as `(name, canonicalized field)` pairs in the order dictated by
+/// `h=`.
+///
+/// The last pair is the DKIM-Signature header itself, with an empty `b=` and no trailing CRLF.
+/// Concatenating all fields yields [`DkimComponents::canonicalized_header`], the exact bytes
+/// that are hashed.
+pub fn parse_dkim_header_lines(raw_email: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    parse_dkim_header_lines_with_options(raw_email, DkimOptions::default())
+}
+
+/// Same as [`parse_dkim_header_lines`], applying `options` to the raw email first.
+pub fn parse_dkim_header_lines_with_options(
+    raw_email: &[u8],
+    options: DkimOptions,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let raw_email = apply_options(raw_email, options);
+    let (header, _) = split_header_body(&raw_email);
+    let headers = split_header_fields(header);
+
+    let (raw, fields) = first_signature(&headers)?;
+    let (header_canon, _) = canonicalization_modes(&fields)?;
+
+    Ok(signed_header_lines(&headers, &raw, &fields, header_canon))
+}
+
+/// Returns the header fields signed by the first DKIM signature of a raw email as they appear on
+/// the wire, in the order dictated by `h=` and with CRLF line endings. The DKIM-Signature header
+/// itself is not included.
+///
+/// Fields the signature does not select are left out, but the bytes of the selected ones are
+/// only covered up to canonicalization: with `relaxed` header canonicalization their case and
+/// whitespace can be changed without breaking the signature.
+pub fn signed_raw_header(raw_email: &[u8]) -> Result<Vec<u8>> {
+    signed_raw_header_with_options(raw_email, DkimOptions::default())
+}
+
+/// Same as [`signed_raw_header`], applying `options` to the raw email first.
+pub fn signed_raw_header_with_options(raw_email: &[u8], options: DkimOptions) -> Result<Vec<u8>> {
+    let raw_email = apply_options(raw_email, options);
+    let (header, _) = split_header_body(&raw_email);
+    let headers = split_header_fields(header);
+
+    let (_, fields) = first_signature(&headers)?;
+    Ok(selected_header_fields(&headers, &fields)
+        .into_iter()
+        .flat_map(|field| field.raw.iter().copied())
+        .collect())
+}
+
+/// Returns the tags of the first DKIM signature of a raw email along with the body it signs, see
+/// [`signed_body`]. Later signatures are not parsed.
+pub(crate) fn first_signed_body(
+    raw_email: &[u8],
+    options: DkimOptions,
+) -> Result<(HashMap<String, String>, Vec<u8>)> {
+    let raw_email = apply_options(raw_email, options);
+    let (header, body) = split_header_body(&raw_email);
+    let (_, fields) = first_signature(&split_header_fields(header))?;
+    let body = signed_body(body, &fields)?;
+    Ok((fields, body))
+}
+
+/// Compares the content covered by the first DKIM signature of `original` with the same content
+/// of `modified`, e.g. to find out what broke the signature of a forwarded copy.
+///
+/// Both emails are canonicalized as declared by the original signature's `c=` tag, with the
+/// headers it selects through `h=` and the body truncated to its `l=` tag. The signature headers
+/// themselves are not compared. Line endings are compared as they are, so an email whose CRLFs
+/// were converted to LF shows up as modified.
+pub fn diff_signed_content(original: &[u8], modified: &[u8]) -> Result<SignedDiff> {
+    diff_signed_content_with_options(original, modified, DkimOptions::default())
+}
+
+/// Same as [`diff_signed_content`], applying `options` to both emails first.
+pub fn diff_signed_content_with_options(
+    original: &[u8],
+    modified: &[u8],
+    options: DkimOptions,
+) -> Result<SignedDiff> {
+    let original = apply_options(original, options);
+    let modified = apply_options(modified, options);
+    let (original_header, original_body) = split_header_body(&original);
+    let (modified_header, modified_body) = split_header_body(&modified);
+    let original_headers = split_header_fields(original_header);
+    let modified_headers = split_header_fields(modified_header);
+
+    let (raw, fields) = first_signature(&original_headers)?;
+    let (header_canon, _) = canonicalization_modes(&fields)?;
+
+    let original_lines = signed_header_lines(&original_headers, &raw, &fields, header_canon);
+    let modified_lines = signed_header_lines(&modified_headers, &raw, &fields, header_canon);
+    let first_divergent_header = (0..original_lines.len().max(modified_lines.len()))
+        .find(|&i| original_lines.get(i) != modified_lines.get(i))
+        .and_then(|i| original_lines.get(i).or(modified_lines.get(i)))
+        .map(|(name, _)| name.clone());
+
+    let hash = signature_hash(&fields)?;
+    let body_hash = |body: &[u8]| signed_body(body, &fields).map(|body| hash.digest(&body));
+
+    Ok(SignedDiff {
+        headers_differ: first_divergent_header.is_some(),
+        body_hash_differs: body_hash(original_body)? != body_hash(modified_body)?,
+        first_divergent_header,
+    })
+}
+
+/// Verifies the DKIM signatures of a raw email in header order and returns the index of the
+/// first one that passes.
+///
+/// `key_for` supplies the public key of a signature, typically looked up from its `d=` and `s=`
+/// tags. Signatures it returns `None` for, and signatures that cannot be parsed, are skipped.
+pub fn verify_any<F>(raw_email: &[u8], mut key_for: F) -> Result<usize>
+where
+    F: FnMut(&DkimComponents) -> Option<RsaPublicKey>,
+{
+    let components = parse_all_dkim_components(raw_email);
+    if components.is_empty() {
+        return Err(anyhow!("No DKIM signatures found"));
+    }
+    components
+        .iter()
+        .position(|signature| {
+            signature.as_ref().is_ok_and(|signature| {
+                key_for(signature)
+                    .is_some_and(|key| verify_dkim_components(signature, &key).unwrap_or(false))
+            })
+        })
+        .ok_or_else(|| anyhow!("None of the {} DKIM signatures verified", components.len()))
+}
+
+/// Checks the body hash and the `rsa-sha256` or `rsa-sha512` signature of a single DKIM
+/// signature.
+pub fn verify_dkim_components(
+    components: &DkimComponents,
+    public_key: &RsaPublicKey,
+) -> Result<bool> {
+    let (body_hash_valid, signature_valid) = check_dkim_components(components, public_key)?;
+    Ok(body_hash_valid && signature_valid)
+}
+
+/// Verifies a raw email against a pre-fetched public key, without any DNS lookup.
+///
+/// Signatures whose `h=` tag does not cover `From` are skipped, since RFC 6376 section 5.4
+/// requires it to be signed. The result describes the first remaining signature that fully
+/// verifies, or the first remaining one if none does; signatures that cannot be parsed or are
+/// not RSA signatures are reported as invalid. Errors are only returned for emails without a
+/// signature covering `From`.
+pub fn verify_email_offline(
+    eml_content: &[u8],
+    public_key: &RsaPublicKey,
+) -> Result<VerificationResult> {
+    verify_email_offline_with_options(eml_content, public_key, DkimOptions::default())
+}
+
+/// Same as [`verify_email_offline`], applying `options` to the raw email first.
+pub fn verify_email_offline_with_options(
+    eml_content: &[u8],
+    public_key: &RsaPublicKey,
+    options: DkimOptions,
+) -> Result<VerificationResult> {
+    let mut results: Vec<VerificationResult> = all_signatures(&apply_options(eml_content, options))
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (fields, _))| requires_header(fields, "From"))
+        .map(|(signature_index, (fields, components))| {
+            let (body_hash_valid, signature_valid) = components
+                .and_then(|components| check_dkim_components(&components, public_key))
+                .unwrap_or((false, false));
+            let tag = |name: &str| fields.get(name).cloned().unwrap_or_default();
+            VerificationResult {
+                signature_index,
+                domain: tag("d"),
+                selector: tag("s"),
+                body_hash_valid,
+                signature_valid,
+            }
+        })
+        .collect();
+    if results.is_empty() {
+        return Err(anyhow!("No DKIM signature covering the From header found"));
+    }
+
+    let index = results
+        .iter()
+        .position(VerificationResult::is_valid)
+        .unwrap_or(0);
+    Ok(results.swap_remove(index))
+}
+
+/// Signs a raw email with a DKIM signature carrying `tags`, e.g.
+/// `v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1; h=from:subject`, and returns it
+/// with the new DKIM-Signature header prepended.
+///
+/// The `bh=` and `b=` tags are computed and appended, hashing with the `a=` algorithm. This is
+/// meant for generating test fixtures with throwaway keys, not for signing outgoing mail.
+pub fn sign_dkim(raw_email: &[u8], tags: &str, private_key: &RsaPrivateKey) -> Result<Vec<u8>> {
+    let hash = rsa_signature_hash(&parse_dkim_tags(tags))?;
+    let signature_header =
+        |body_hash: &str| format!("DKIM-Signature: {}; bh={}; b=", tags.trim(), body_hash);
+    let with_header = |header: &str| [header.as_bytes(), b"\r\n", raw_email].concat();
+
+    // The body hash and the signed header blob are computed by the same code that verifies them
+    let body_hash = compute_body_hash(&with_header(&signature_header("")))?;
+    let unsigned_header = signature_header(&body_hash);
+    let signed_data: Vec<u8> = parse_dkim_header_lines(&with_header(&unsigned_header))?
+        .into_iter()
+        .flat_map(|(_, line)| line)
+        .collect();
+    let signature = sign_rsa(private_key, hash, &signed_data)?;
+
+    Ok(with_header(
+        &(unsigned_header + &STANDARD.encode(signature)),
+    ))
+}
+
+/// Returns whether the body hash and the signature of a DKIM signature verify, in that order.
+fn check_dkim_components(
+    components: &DkimComponents,
+    public_key: &RsaPublicKey,
+) -> Result<(bool, bool)> {
+    let tag = |name: &str| {
+        components
+            .fields
+            .get(name)
+            .ok_or_else(|| anyhow!("DKIM-Signature is missing the {}= tag", name))
+    };
+
+    let hash = rsa_signature_hash(&components.fields)?;
+
+    let body_length = components
+        .fields
+        .get("l")
+        .map(|length| length.parse::<usize>())
+        .transpose()?;
+    let body_hash_valid = verify_body_with_hash(
+        &components.canonicalized_body,
+        tag("bh")?,
+        body_length,
+        hash,
+    )?;
+    let signature_valid = verify_rsa(
+        public_key,
+        hash,
+        &components.canonicalized_header,
+        &components.signature,
+    );
+
+    Ok((body_hash_valid, signature_valid))
+}
+
+/// A header field of a raw email: its name and its raw bytes, including folded lines and the
+/// terminating CRLF.
+struct RawHeader<'a> {
+    name: String,
+    raw: &'a [u8],
+}
+
+/// Splits a CRLF email into its header block (with the CRLF of its last field) and its body.
+fn split_header_body(raw_email: &[u8]) -> (&[u8], &[u8]) {
+    match raw_email
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+    {
+        Some(end) => (&raw_email[..end + 2], &raw_email[end + 4..]),
+        None => (raw_email, &[]),
+    }
+}
+
+fn split_header_fields(header: &[u8]) -> Vec<RawHeader<'_>> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut start = 0;
+    for line in header.split_inclusive(|&byte| byte == b'\n') {
+        let end = start + line.len();
+        match ranges.last_mut() {
+            Some(range) if line.starts_with(b" ") || line.starts_with(b"\t") => range.1 = end,
+            _ => ranges.push((start, end)),
+        }
+        start = end;
+    }
+
+    ranges
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let raw = &header[start..end];
+            let colon = raw.iter().position(|&byte| byte == b':')?;
+            Some(RawHeader {
+                name: String::from_utf8_lossy(&raw[..colon]).trim().to_string(),
+                raw,
+            })
+        })
+        .collect()
+}
+
+/// Returns the raw email, with its line endings normalized if `options` asks for it.
+pub(crate) fn apply_options(raw_email: &[u8], options: DkimOptions) -> Cow<'_, [u8]> {
+    if options.normalize_line_endings {
+        Cow::Owned(normalize_line_endings(raw_email))
+    } else {
+        Cow::Borrowed(raw_email)
+    }
+}
+
+/// Returns the tags and the components of every DKIM-Signature header of a raw email, in header
+/// order.
+fn all_signatures(raw_email: &[u8]) -> Vec<(HashMap<String, String>, Result<DkimComponents>)> {
+    let (header, body) = split_header_body(raw_email);
+    let headers = split_header_fields(header);
+
+    headers
+        .iter()
+        .filter(|field| field.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .map(|signature| {
+            let (_, fields) = signature_tags(signature);
+            (fields, signature_components(&headers, signature, body))
+        })
+        .collect()
+}
+
+/// Returns the raw field and the tags of the first DKIM-Signature header among `headers`.
+fn first_signature(headers: &[RawHeader]) -> Result<(String, HashMap<String, String>)> {
+    let signature = headers
+        .iter()
+        .find(|field| field.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .ok_or_else(|| anyhow!("No DKIM signatures found"))?;
+    Ok(signature_tags(signature))
+}
+
+/// Returns the raw field and the tags of a DKIM-Signature header.
+fn signature_tags(signature: &RawHeader) -> (String, HashMap<String, String>) {
+    let raw = String::from_utf8_lossy(signature.raw).into_owned();
+    let fields = parse_dkim_tags(raw.split_once(':').map_or("", |(_, value)| value));
+    (raw, fields)
+}
+
+fn signature_components(
+    headers: &[RawHeader],
+    signature: &RawHeader,
+    body: &[u8],
+) -> Result<DkimComponents> {
+    let (raw, fields) = signature_tags(signature);
+
+    let (header_canon, body_canon) = canonicalization_modes(&fields)?;
+    let canonicalized_body = match body_canon {
+        BodyCanon::Simple => canonicalize_body_simple(body),
+        BodyCanon::Relaxed => canonicalize_body_relaxed(body),
+    };
+    let canonicalized_header = signed_header_lines(headers, &raw, &fields, header_canon)
+        .into_iter()
+        .flat_map(|(_, line)| line)
+        .collect();
+
+    let signature = fields
+        .get("b")
+        .map(|b| STANDARD.decode(b.split_whitespace().collect::<String>()))
+        .transpose()?
+        .ok_or_else(|| anyhow!("DKIM-Signature is missing the b= tag"))?;
+
+    Ok(DkimComponents {
+        fields,
+        canonicalized_header,
+        canonicalized_body,
+        signature,
+    })
+}
+
+/// Returns the header and body canonicalizations declared by the `c=` tag of a DKIM signature.
+///
+/// A missing `c=` tag means `simple/simple`, and a single algorithm such as `c=relaxed` applies
+/// to the header only, with `simple` for the body (RFC 6376 section 3.5). A `c=` tag naming an
+/// unknown algorithm also yields `simple/simple`; verification rejects such signatures instead.
+pub fn parse_canonicalization(fields: &HashMap<String, String>) -> (HeaderCanon, BodyCanon) {
+    canonicalization_modes(fields).unwrap_or_default()
+}
+
+/// Same as [`parse_canonicalization`], failing on unknown algorithms.
+fn canonicalization_modes(fields: &HashMap<String, String>) -> Result<(HeaderCanon, BodyCanon)> {
+    let (header_canon, body_canon) = canonicalization_names(fields);
+    let header_canon = match header_canon {
+        "simple" => HeaderCanon::Simple,
+        "relaxed" => HeaderCanon::Relaxed,
+        other => return Err(anyhow!("Unsupported header canonicalization: {}", other)),
+    };
+    let body_canon = match body_canon {
+        "simple" => BodyCanon::Simple,
+        "relaxed" => BodyCanon::Relaxed,
+        other => return Err(anyhow!("Unsupported body canonicalization: {}", other)),
+    };
+    Ok((header_canon, body_canon))
+}
+
+/// Splits the `c=` tag into its trimmed header and body algorithm names, applying the RFC 6376
+/// defaults.
+fn canonicalization_names(fields: &HashMap<String, String>) -> (&str, &str) {
+    let canonicalization = fields.get("c").map_or("simple/simple", String::as_str);
+    let (header_canon, body_canon) = canonicalization
+        .split_once('/')
+        .unwrap_or((canonicalization, "simple"));
+    (header_canon.trim(), body_canon.trim())
+}
+
+/// Returns the header fields selected by the `h=` tag of a DKIM signature, in signing order.
+fn selected_header_fields<'h, 'a>(
+    headers: &'h [RawHeader<'a>],
+    fields: &HashMap<String, String>,
+) -> Vec<&'h RawHeader<'a>> {
+    // Each name in h= selects the last not yet selected field with that name, per RFC 6376
+    // section 5.4.2; names without a matching field contribute nothing
+    let mut selected = vec![false; headers.len()];
+    fields
+        .get("h")
+        .map_or("", String::as_str)
+        .split(':')
+        .filter_map(|name| {
+            let name = name.trim();
+            let index = (0..headers.len())
+                .rev()
+                .find(|&i| !selected[i] && headers[i].name.eq_ignore_ascii_case(name))?;
+            selected[index] = true;
+            Some(&headers[index])
+        })
+        .collect()
+}
+
+/// Returns the hash function of the `a=` algorithm of a DKIM signature.
+pub(crate) fn signature_hash(fields: &HashMap<String, String>) -> Result<HashAlgorithm> {
+    let algorithm = fields
+        .get("a")
+        .ok_or_else(|| anyhow!("DKIM-Signature is missing the a= tag"))?;
+    HashAlgorithm::from_dkim_algorithm(algorithm)
+        .ok_or_else(|| anyhow!("Unsupported DKIM algorithm: {}", algorithm))
+}
+
+/// Same as [`signature_hash`], rejecting algorithms other than RSA, such as `ed25519-sha256`.
+fn rsa_signature_hash(fields: &HashMap<String, String>) -> Result<HashAlgorithm> {
+    if let Some(algorithm) = fields
+        .get("a")
+        .filter(|algorithm| !algorithm.trim().to_ascii_lowercase().starts_with("rsa-"))
+    {
+        return Err(anyhow!("Unsupported DKIM algorithm: {}", algorithm));
+    }
+    signature_hash(fields)
+}
+
+/// Canonicalizes a body as declared by the `c=` tag of a DKIM signature and truncates it to its
+/// `l=` tag, yielding the bytes covered by the `bh=` body hash.
+fn signed_body(body: &[u8], fields: &HashMap<String, String>) -> Result<Vec<u8>> {
+    let (_, body_canon) = canonicalization_modes(fields)?;
+    let mut body = match body_canon {
+        BodyCanon::Simple => canonicalize_body_simple(body),
+        BodyCanon::Relaxed => canonicalize_body_relaxed(body),
+    };
+    if let Some(length) = fields.get("l") {
+        body.truncate(length.parse::<usize>()?);
+    }
+    Ok(body)
+}
+
+/// Canonicalizes the fields selected by `h=` in signing order, followed by the signature header
+/// `raw_signature`, whose lines concatenated form the signed header blob.
+fn signed_header_lines(
+    headers: &[RawHeader],
+    raw_signature: &str,
+    fields: &HashMap<String, String>,
+    header_canon: HeaderCanon,
+) -> Vec<(String, Vec<u8>)> {
+    let canonicalize = |raw: &[u8]| match header_canon {
+        HeaderCanon::Simple => raw.to_vec(),
+        HeaderCanon::Relaxed => canonicalize_header_relaxed(raw),
+    };
+
+    let mut lines: Vec<(String, Vec<u8>)> = selected_header_fields(headers, fields)
+        .into_iter()
+        .map(|field| (field.name.clone(), canonicalize(field.raw)))
+        .collect();
+
+    // The signature header itself is hashed last, with an empty b= and no trailing CRLF
+    let mut signature_header = canonicalize(without_signature_value(raw_signature).as_bytes());
+    if signature_header.ends_with(b"\r\n") {
+        signature_header.truncate(signature_header.len() - 2);
+    }
+    lines.push(("DKIM-Signature".to_string(), signature_header));
+    lines
+}
+
+/// Removes the value of the b= tag of a raw DKIM-Signature field, including the whitespace
+/// around it.
+fn without_signature_value(raw: &str) -> String {
+    raw.split(';')
+        .map(|tag| match tag.split_once('=') {
+            Some((name, _)) if name.trim() == "b" => format!("{}=", name),
+            _ => tag.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Canonicalizes a header field with the DKIM "relaxed" algorithm (RFC 6376 section 3.4.2).
+fn canonicalize_header_relaxed(raw: &[u8]) -> Vec<u8> {
+    let colon = raw
+        .iter()
+        .position(|&byte| byte == b':')
+        .unwrap_or(raw.len());
+    let mut canonicalized = String::from_utf8_lossy(&raw[..colon])
+        .trim()
+        .to_lowercase()
+        .into_bytes();
+    canonicalized.push(b':');
+
+    let mut value: Vec<u8> = Vec::new();
+    let mut pending_space = false;
+    for &byte in raw.get(colon + 1..).unwrap_or_default() {
+        match byte {
+            b'\r' | b'\n' => {}
+            b' ' | b'\t' => pending_space = true,
+            _ => {
+                if pending_space && !value.is_empty() {
+                    value.push(b' ');
+                }
+                pending_space = false;
+                value.push(byte);
+            }
+        }
+    }
+
+    canonicalized.extend(value);
+    canonicalized.extend_from_slice(b"\r\n");
+    canonicalized
+}
+
+/// Checks that the `i=` agent or user identifier (AUID) of a DKIM signature belongs to its `d=`
+/// domain, i.e. that its domain is the `d=` domain or one of its subdomains (RFC 6376 section
+/// 3.5). Domains are compared case-insensitively.
+///
+/// A missing `i=` tag is valid, since it defaults to `@` followed by the `d=` domain.
+pub fn validate_auid_scope(fields: &HashMap<String, String>) -> Result<(), AuidError> {
+    let domain = fields.get("d").ok_or(AuidError::MissingDomain)?;
+    let Some(auid) = fields.get("i") else {
+        return Ok(());
+    };
+
+    let (_, auid_domain) = auid
+        .rsplit_once('@')
+        .filter(|(_, auid_domain)| !auid_domain.is_empty())
+        .ok_or_else(|| AuidError::Malformed(auid.clone()))?;
+    let (auid_domain, domain) = (auid_domain.to_lowercase(), domain.to_lowercase());
+    if is_within_domain(&auid_domain, &domain) {
+        Ok(())
+    } else {
+        Err(AuidError::OutOfScope {
+            auid_domain,
+            domain,
+        })
+    }
+}
+
+/// Whether `domain` is `parent` or one of its subdomains. Both must be lowercased.
+pub(crate) fn is_within_domain(domain: &str, parent: &str) -> bool {
+    domain == parent || domain.ends_with(&format!(".{}", parent))
+}
+
+/// Rejects a signature whose `x=` expiration is in the past or whose `t=` timestamp is in the
+/// future relative to `now`. Missing tags are not an error.
+pub fn check_signature_validity(
+    dkim_fields: &HashMap<String, String>,
+    now: SystemTime,
+) -> Result<(), SignatureTimeError> {
+    check_signature_validity_with_skew(dkim_fields, now, Duration::ZERO)
+}
+
+/// Same as [`check_signature_validity`], tolerating up to `skew` of clock drift in either
+/// direction, e.g. 300 seconds.
+///
+/// Errors carry the overshoot beyond `skew`, so the allowance can be tuned from rejected mail.
+pub fn check_signature_validity_with_skew(
+    dkim_fields: &HashMap<String, String>,
+    now: SystemTime,
+    skew: Duration,
+) -> Result<(), SignatureTimeError> {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let skew = skew.as_secs();
+
+    if let Some(expiration) = parse_timestamp_tag(dkim_fields, "x")? {
+        let deadline = expiration.saturating_add(skew);
+        if now > deadline {
+            return Err(SignatureTimeError::Expired {
+                expiration,
+                overshoot: Duration::from_secs(now - deadline),
+            });
+        }
+    }
+
+    if let Some(timestamp) = parse_timestamp_tag(dkim_fields, "t")? {
+        let latest = now.saturating_add(skew);
+        if timestamp > latest {
+            return Err(SignatureTimeError::FutureTimestamp {
+                timestamp,
+                overshoot: Duration::from_secs(timestamp - latest),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_timestamp_tag(
+    dkim_fields: &HashMap<String, String>,
+    tag: &'static str,
+) -> Result<Option<u64>, SignatureTimeError> {
+    dkim_fields
+        .get(tag)
+        .map(|value| value.parse::<u64>())
+        .transpose()
+        .map_err(|_| SignatureTimeError::InvalidTag(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_canonicalize_checked_errors() {
+        let signature = "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; h=from; bh=; b=";
+        let email =
+            |tags: &str| format!("{}{}\r\nFrom: a@example.com\r\n\r\nHi\r\n", signature, tags);
+
+        assert_eq!(
+            canonicalize_checked(b"From: a@example.com\r\n\r\nHi\r\n"),
+            Err(CanonError::MissingSignature)
+        );
+        assert_eq!(
+            canonicalize_checked(b"DKIM-Signature: v=1; d=example.com; b=\r\n\r\nHi\r\n"),
+            Err(CanonError::MissingTags(vec!["a", "bh", "h", "s"]))
+        );
+        assert!(matches!(
+            canonicalize_checked(email("YWJj; c=strict/simple").as_bytes()),
+            Err(CanonError::UnsupportedCanonicalization(e)) if e.contains("strict")
+        ));
+        assert!(matches!(
+            canonicalize_checked(email("not*base64").as_bytes()),
+            Err(CanonError::InvalidSignatureEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_count_dkim_signatures() {
+        assert_eq!(count_dkim_signatures(MULTI_SIGNED_EMAIL), 2);
+        assert_eq!(
+            count_dkim_signatures(b"dkim-signature: v=1; d=example.com\nFrom: a@example.com\n\nHi"),
+            1
+        );
+        assert_eq!(
+            count_dkim_signatures(b"From: a@example.com\r\n\r\nDKIM-Signature: v=1\r\n"),
+            0
+        );
+    }
+
+    #[test]
+    fn test_retain_dkim_signatures() {
+        let second = retain_dkim_signatures(MULTI_SIGNED_EMAIL, |index| index == 1);
+        assert_eq!(count_dkim_signatures(&second), 1);
+        assert_eq!(extract_dkim_fields(&second).unwrap()["d"], "example.com");
+        assert_eq!(
+            extract_all_dkim_fields(MULTI_SIGNED_EMAIL).unwrap()[1],
+            extract_dkim_fields(&second).unwrap()
+        );
+        assert_eq!(
+            MULTI_SIGNED_EMAIL.len() - second.len(),
+            raw_header(MULTI_SIGNED_EMAIL)
+                .split_inclusive(|&byte| byte == b'\n')
+                .next()
+                .unwrap()
+                .len()
+        );
+
+        assert_eq!(
+            retain_dkim_signatures(MULTI_SIGNED_EMAIL, |_| true),
+            MULTI_SIGNED_EMAIL
+        );
+        let lf_email = b"DKIM-Signature: v=1; d=example.com\n\th=from\nFrom: a@example.com\n\nHi";
+        assert_eq!(
+            retain_dkim_signatures(lf_email, |_| false),
+            b"From: a@example.com\n\nHi"
+        );
+    }
+
+    #[test]
+    fn test_extract_dkim_header_summary() {
+        let summary = extract_dkim_header_summary(
+            b"DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/simple; d=example.com; s=s1;\r\n\
+              \th=from:subject; bh=frcCV1k9oG9oKj3dpUqdJg1P\r\n xRT2RSN/XKdLCPjaYaY=; b=abc",
+        )
+        .unwrap();
+        assert_eq!(summary.algorithm, "rsa-sha256");
+        assert_eq!(summary.header_canon, "relaxed");
+        assert_eq!(summary.body_canon, "simple");
+        assert_eq!(summary.selector, "s1");
+        assert_eq!(summary.domain, "example.com");
+        assert_eq!(
+            summary.body_hash,
+            "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
+        );
+
+        let summary =
+            extract_dkim_header_summary(b"a=rsa-sha256; d=example.com; s=s1; bh=abc=").unwrap();
+        assert_eq!(summary.header_canon, "simple");
+        assert_eq!(summary.body_canon, "simple");
+    }
+
+    #[test]
+    fn test_signed_raw_header() {
+        let email =
+            b"DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1;\r\n\
+\th=subject:from; bh=; b=\r\n\
+From: Alice <alice@example.com>\r\n\
+X-Unsigned: injected\r\n\
+Subject:  Invoice\r\n\
+\t42\r\n\
+\r\n\
+Hi\r\n";
+        assert_eq!(
+            signed_raw_header(email).unwrap(),
+            b"Subject:  Invoice\r\n\t42\r\nFrom: Alice <alice@example.com>\r\n"
+        );
+        assert!(signed_raw_header(b"From: a@example.com\r\n\r\nHi").is_err());
+    }
+
+    #[test]
+    fn test_diff_signed_content() {
+        let original =
+            b"DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1;\r\n\
+\th=from:subject:date; bh=; b=abc\r\n\
+From: alice@example.com\r\n\
+Subject: Invoice  42\r\n\
+Date: Tue, 2 Jan 2024 10:30:00 +0100\r\n\
+X-Unsigned: 1\r\n\
+\r\n\
+Total: $42\r\n";
+        let original_text = String::from_utf8_lossy(original);
+        let diff = |modified: String| diff_signed_content(original, modified.as_bytes()).unwrap();
+
+        // Changes that relaxed canonicalization or h= ignore are not reported
+        let reformatted = original_text
+            .replace("Invoice  42", "Invoice 42")
+            .replace("X-Unsigned: 1", "X-Unsigned: 2")
+            .replace("b=abc", "b=xyz");
+        assert!(diff(reformatted).is_unchanged());
+
+        let diff_subject = diff(original_text.replace("Invoice", "Receipt"));
+        assert!(diff_subject.headers_differ && !diff_subject.body_hash_differs);
+        assert_eq!(
+            diff_subject.first_divergent_header.as_deref(),
+            Some("Subject")
+        );
+
+        let diff_missing = diff(original_text.replace("Subject: Invoice  42\r\n", ""));
+        assert_eq!(
+            diff_missing.first_divergent_header.as_deref(),
+            Some("Subject")
+        );
+
+        let diff_body = diff(original_text.replace("$42", "$420"));
+        assert!(!diff_body.headers_differ && diff_body.body_hash_differs);
+
+        let lf = original_text.replace("\r\n", "\n");
+        assert!(!diff(lf.clone()).is_unchanged());
+        let options = DkimOptions {
+            normalize_line_endings: true,
+        };
+        assert!(
+            diff_signed_content_with_options(original, lf.as_bytes(), options)
+                .unwrap()
+                .is_unchanged()
+        );
+
+        assert!(diff_signed_content(b"From: a@example.com\r\n\r\n", original).is_err());
+    }
+
+    #[test]
+    fn test_parse_canonicalization() {
+        let modes = |tags: &str| parse_canonicalization(&parse_dkim_tags(tags));
+        assert_eq!(
+            modes("d=example.com"),
+            (HeaderCanon::Simple, BodyCanon::Simple)
+        );
+        assert_eq!(
+            modes("c=relaxed/relaxed"),
+            (HeaderCanon::Relaxed, BodyCanon::Relaxed)
+        );
+        assert_eq!(
+            modes("c=simple/relaxed"),
+            (HeaderCanon::Simple, BodyCanon::Relaxed)
+        );
+        assert_eq!(
+            modes("c=relaxed"),
+            (HeaderCanon::Relaxed, BodyCanon::Simple)
+        );
+        assert_eq!(
+            modes("c=nowsp/simple"),
+            (HeaderCanon::Simple, BodyCanon::Simple)
+        );
+        assert!(canonicalization_modes(&parse_dkim_tags("c=nowsp/simple")).is_err());
+    }
+
+    #[test]
+    fn test_dkim_tag_audit() {
+        let audit = dkim_tag_audit(
+            b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; bh=abc=; c=relaxed; foo=bar",
+        );
+        assert!(!audit.is_complete());
+        assert_eq!(audit.missing, ["b", "h"]);
+        assert_eq!(audit.unknown, ["foo"]);
+        assert_eq!(audit.present["d"], "example.com");
+        assert_eq!(audit.present.len(), 7);
+
+        let audit = dkim_tag_audit(b"v=1; a=rsa-sha256; b=x; bh=y; d=example.com; h=from; s=s1");
+        assert!(audit.is_complete());
+        assert!(audit.unknown.is_empty());
+    }
+
+    #[test]
+    fn test_extract_signed_date_unix() {
+        let email =
+            b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; h=From:Date; bh=; b=\r\n\
+From: alice@example.com\r\n\
+Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\
+\r\n\
+body\r\n";
+        assert_eq!(extract_signed_date_unix(email).unwrap(), Some(1057049557));
+
+        let unsigned =
+            b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; h=From; bh=; b=\r\n\
+Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\
+\r\n";
+        assert_eq!(extract_signed_date_unix(unsigned).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_body_content_type() {
+        let email = b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; h=From:Content-Type; bh=; b=\r\n\
+Content-Type: text/html; charset=UTF-8\r\n\
+\r\n";
+        assert!(verify_body_content_type(email, "text/html").unwrap());
+        assert!(!verify_body_content_type(email, "text/plain").unwrap());
+
+        let unsigned =
+            b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; h=From; bh=; b=\r\n\
+Content-Type: text/html\r\n\
+\r\n";
+        assert!(!verify_body_content_type(unsigned, "text/html").unwrap());
+    }
+
+    /// Signed with the 512-bit test key `DKIM_TEST_KEY`: the first signature (`d=example.net`)
+    /// uses simple/simple, the second (`d=example.com`) relaxed/relaxed with a folded `b=`.
+    const MULTI_SIGNED_EMAIL: &[u8] = b"DKIM-Signature: v=1; a=rsa-sha256; c=simple/simple; d=example.net; s=s0; h=From:Subject; bh=BovV/3x9Vb3hktb6mhrYYLZI7yowWChfVcgcXufWpB4=; b=QctLwojmh9DZv0uQkEpxj6HUd0q2h34FoK3vioV2v/SeTndz8Z9jT4sKY8RoLFF4lu2TLkAf2I4keqOaVb3y/Q==\r\n\
+DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1;\r\n\
+\th=from:to:subject:date; bh=lGP7WgYuC2BfNW+3tXtZjgpRyTmfyliAJh62YXMvN6E=; b=qXA16OpnWRww1kj3e+yG1UcPQ2H3cgxvz+z6tUPA\r\n\
+\tn1Y0lhQ05Ubo2zI50l5M4Zm4nfaLtYumaYUPUZVWD0R9VQ==\r\n\
+From: Alice <alice@example.com>\r\n\
+To: bob@example.org\r\n\
+Subject: Multiple\r\n\
+\x20 signatures here\r\n\
+Date: Tue, 1 Jul 2003 10:52:37 +0200\r\n\
+\r\n\
+Hello  Bob,\r\n\
+\r\n\
+\r\n";
+
+    const DKIM_TEST_KEY: &str = "MFwwDQYJKoZIhvcNAQEBBQADSwAwSAJBAMfNjWfT9pb20Iki41NjLcGLV5Kxm1IE\
+                                 pj0pquGDfBFBG075dWtaKB1IS5oGq8vFtdTeVf8kujALfQVo1UJZriMCAwEAAQ==";
+
+    #[test]
+    fn test_parse_all_dkim_components() {
+        let components: Vec<DkimComponents> = parse_all_dkim_components(MULTI_SIGNED_EMAIL)
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(components.len(), 2);
+        assert_eq!(components[0].fields["d"], "example.net");
+        assert_eq!(components[1].fields["d"], "example.com");
+        assert_eq!(components[1].canonicalized_body, b"Hello Bob,\r\n");
+        assert!(components[1]
+            .canonicalized_header
+            .starts_with(b"from:Alice <alice@example.com>\r\nto:bob@example.org\r\n"));
+    }
+
+    #[test]
+    fn test_parse_dkim_header_lines() {
+        let lines = parse_dkim_header_lines(MULTI_SIGNED_EMAIL).unwrap();
+        let components = parse_all_dkim_components(MULTI_SIGNED_EMAIL);
+
+        let names: Vec<&str> = lines.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["From", "Subject", "DKIM-Signature"]);
+        assert_eq!(lines[1].1, b"Subject: Multiple\r\n  signatures here\r\n");
+        assert_eq!(
+            lines
+                .into_iter()
+                .flat_map(|(_, line)| line)
+                .collect::<Vec<u8>>(),
+            components[0].as_ref().unwrap().canonicalized_header
+        );
+    }
+
+    #[test]
+    fn test_verify_any() {
+        let key = crate::parse_rsa_public_key(DKIM_TEST_KEY).unwrap();
+
+        assert_eq!(
+            verify_any(MULTI_SIGNED_EMAIL, |_| Some(key.clone())).unwrap(),
+            0
+        );
+        assert_eq!(
+            verify_any(MULTI_SIGNED_EMAIL, |signature| {
+                (signature.fields["d"] == "example.com").then(|| key.clone())
+            })
+            .unwrap(),
+            1
+        );
+
+        let tampered = String::from_utf8_lossy(MULTI_SIGNED_EMAIL).replace("Bob,", "Eve,");
+        assert!(verify_any(tampered.as_bytes(), |_| Some(key.clone())).is_err());
+    }
+
+    #[test]
+    fn test_verify_any_skips_malformed_signatures() {
+        let key = crate::parse_rsa_public_key(DKIM_TEST_KEY).unwrap();
+        let email = [
+            b"DKIM-Signature: v=1; a=rsa-sha256; c=bogus; d=example.org; s=s1; h=From; bh=; b=!\r\n"
+                .as_slice(),
+            MULTI_SIGNED_EMAIL,
+        ]
+        .concat();
+
+        let components = parse_all_dkim_components(&email);
+        assert_eq!(components.len(), 3);
+        assert!(components[0].is_err());
+        assert!(components[1].is_ok());
+        assert_eq!(verify_any(&email, |_| Some(key.clone())).unwrap(), 1);
+        assert!(verify_any(b"From: a@example.com\r\n\r\nHi\r\n", |_| Some(key.clone())).is_err());
+    }
+
+    #[test]
+    fn test_verify_email_offline() {
+        let key = crate::parse_rsa_public_key(DKIM_TEST_KEY).unwrap();
+
+        let result = verify_email_offline(MULTI_SIGNED_EMAIL, &key).unwrap();
+        assert!(result.is_valid());
+        assert_eq!(result.signature_index, 0);
+        assert_eq!(result.domain, "example.net");
+        assert_eq!(result.selector, "s0");
+
+        let tampered = String::from_utf8_lossy(MULTI_SIGNED_EMAIL).replace("Bob,", "Eve,");
+        let result = verify_email_offline(tampered.as_bytes(), &key).unwrap();
+        assert!(!result.body_hash_valid);
+        assert!(result.signature_valid);
+
+        // Signatures of other algorithms are reported as invalid instead of failing the email
+        let ed25519 = [
+            b"DKIM-Signature: v=1; a=ed25519-sha256; d=example.org; s=s1; h=From; bh=; b=\r\n"
+                .as_slice(),
+            MULTI_SIGNED_EMAIL,
+        ]
+        .concat();
+        let result = verify_email_offline(&ed25519, &key).unwrap();
+        assert!(result.is_valid());
+        assert_eq!(result.signature_index, 1);
+
+        // Line endings are only normalized on request
+        let lf = String::from_utf8_lossy(MULTI_SIGNED_EMAIL).replace("\r\n", "\n");
+        assert!(!verify_email_offline(lf.as_bytes(), &key)
+            .unwrap()
+            .is_valid());
+        let options = DkimOptions {
+            normalize_line_endings: true,
+        };
+        assert!(
+            verify_email_offline_with_options(lf.as_bytes(), &key, options)
+                .unwrap()
+                .is_valid()
+        );
+    }
+
+    #[test]
+    fn test_verify_email_offline_requires_signed_from() {
+        let private_key = RsaPrivateKey::from_pkcs1_pem(crate::TEST_PRIVATE_KEY).unwrap();
+        let public_key = private_key.to_public_key();
+        let sign = |signed_headers: &str| {
+            sign_dkim(
+                b"From: alice@example.com\r\nSubject: Hi\r\n\r\nHi\r\n",
+                &format!(
+                    "v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1; h={}",
+                    signed_headers
+                ),
+                &private_key,
+            )
+            .unwrap()
+        };
+
+        assert!(verify_email_offline(&sign("from:subject"), &public_key)
+            .unwrap()
+            .is_valid());
+        assert!(verify_email_offline(&sign("subject"), &public_key).is_err());
+    }
+
+    #[test]
+    fn test_signed_headers() {
+        let fields = parse_dkim_tags("v=1; h=From : Subject:\r\n\tDate:from; d=example.com");
+        assert_eq!(
+            signed_headers(&fields),
+            vec!["from", "subject", "date", "from"]
+        );
+        assert!(requires_header(&fields, "FROM"));
+        assert!(!requires_header(&fields, "To"));
+        assert!(signed_headers(&parse_dkim_tags("v=1")).is_empty());
+    }
+
+    #[test]
+    fn test_extract_arc_sets() {
+        let email = b"ARC-Seal: i=2; a=rsa-sha256; cv=pass; d=relay.example; s=arc; b=c2VhbDI=\r\n\
+            ARC-Message-Signature: i=2; a=rsa-sha256; d=relay.example; s=arc; h=from; bh=x; b=y\r\n\
+            ARC-Authentication-Results: i=2; relay.example; dkim=pass\r\n\
+            ARC-Authentication-Results: i=4; late.example; dkim=fail\r\n\
+            ARC-Seal: i=1; a=rsa-sha256; cv=none; d=list.example; s=arc; b=c2VhbDE=\r\n\
+            ARC-Message-Signature: i=1; a=rsa-sha256; d=list.example; s=arc; h=from; bh=x; b=y\r\n\
+            ARC-Authentication-Results: i=1; list.example; spf=pass\r\n\
+            From: alice@example.com\r\n\r\nHi\r\n";
+
+        let sets = extract_arc_sets(email).unwrap();
+        assert_eq!(
+            sets.iter().map(|set| set.instance).collect::<Vec<_>>(),
+            vec![1, 2, 4]
+        );
+        assert!(sets[0].is_complete() && !sets[0].gap_before);
+        assert_eq!(sets[0].seal.as_ref().unwrap()["d"], "list.example");
+        assert!(sets[1].is_complete() && !sets[1].gap_before);
+        assert_eq!(
+            sets[2].authentication_results.as_deref(),
+            Some("i=4; late.example; dkim=fail")
+        );
+        assert!(!sets[2].is_complete() && sets[2].gap_before);
+
+        assert!(extract_arc_sets(b"From: a@example.com\r\n\r\n")
+            .unwrap()
+            .is_empty());
+        assert!(extract_arc_sets(b"ARC-Seal: cv=none; b=x\r\n\r\n").is_err());
+        assert!(extract_arc_sets(b"ARC-Seal: i=1; b=x\r\nARC-Seal: i=1; b=y\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_validate_auid_scope() {
+        let fields = |tags: &str| parse_dkim_tags(tags);
+        assert_eq!(validate_auid_scope(&fields("d=example.com")), Ok(()));
+        assert_eq!(
+            validate_auid_scope(&fields("d=example.com; i=@example.com")),
+            Ok(())
+        );
+        assert_eq!(
+            validate_auid_scope(&fields("d=Example.com; i=alice@mail.example.COM")),
+            Ok(())
+        );
+        assert_eq!(
+            validate_auid_scope(&fields("d=example.com; i=alice@notexample.com")),
+            Err(AuidError::OutOfScope {
+                auid_domain: "notexample.com".to_string(),
+                domain: "example.com".to_string(),
+            })
+        );
+        assert_eq!(
+            validate_auid_scope(&fields("d=example.com; i=alice")),
+            Err(AuidError::Malformed("alice".to_string()))
+        );
+        assert_eq!(
+            validate_auid_scope(&fields("i=@example.com")),
+            Err(AuidError::MissingDomain)
+        );
+    }
+
+    #[test]
+    fn test_check_signature_validity() {
+        let fields = parse_dkim_tags("v=1; a=rsa-sha256; t=1000; x=2000; d=example.com");
+        assert_eq!(check_signature_validity(&fields, at(1500)), Ok(()));
+        assert_eq!(
+            check_signature_validity(&fields, at(2001)),
+            Err(SignatureTimeError::Expired {
+                expiration: 2000,
+                overshoot: Duration::from_secs(1)
+            })
+        );
+        assert_eq!(
+            check_signature_validity(&fields, at(999)),
+            Err(SignatureTimeError::FutureTimestamp {
+                timestamp: 1000,
+                overshoot: Duration::from_secs(1)
+            })
+        );
+        assert_eq!(
+            check_signature_validity_with_skew(&fields, at(2010), Duration::from_secs(30)),
+            Ok(())
+        );
+        assert_eq!(
+            check_signature_validity_with_skew(&fields, at(2100), Duration::from_secs(30)),
+            Err(SignatureTimeError::Expired {
+                expiration: 2000,
+                overshoot: Duration::from_secs(70)
+            })
+        );
+        assert_eq!(
+            check_signature_validity_with_skew(&fields, at(900), Duration::from_secs(30)),
+            Err(SignatureTimeError::FutureTimestamp {
+                timestamp: 1000,
+                overshoot: Duration::from_secs(70)
+            })
+        );
+    }
+}
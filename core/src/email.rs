@@ -1,47 +1,875 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use cfdkim::{verify_email_with_key, DkimPublicKey};
-use mailparse::{parse_mail, ParsedMail};
+use chrono::{DateTime, Utc};
+use mailparse::{addrparse_header, dateparse, parse_mail, MailHeaderMap, ParsedMail};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use slog::Logger;
 
-use crate::Email;
+use crate::{
+    apply_options, count_dkim_signatures, extract_all_dkim_fields, extract_dkim_fields,
+    first_signed_body, hash_bytes, parse_dkim_tags, requires_header, retain_dkim_signatures,
+    signature_hash, validate_auid_scope, BodyHashReport, CommitmentVersion, Email, HashAlgorithm,
+    MatchTarget, PublicKey, VerifyError,
+};
 
-pub fn extract_email_body(parsed_email: &ParsedMail) -> Vec<u8> {
-    parsed_email
-        .subparts
-        .iter()
-        .find(|part| part.ctype.mimetype == "text/html")
-        .map_or_else(
-            || {
-                parsed_email
-                    .subparts
-                    .first()
-                    .map_or(parsed_email.get_body_raw().unwrap(), |part| {
-                        part.get_body_raw().unwrap()
-                    })
-            },
-            |part| part.get_body_raw().unwrap(),
-        )
+/// Batches smaller than this are processed sequentially even with the `parallel` feature, since
+/// spreading them over threads costs more than it saves.
+#[cfg(feature = "parallel")]
+const PARALLEL_BATCH_THRESHOLD: usize = 16;
+
+/// MIME types of detached signature parts, which must never be selected as the email body.
+const SIGNATURE_MIMETYPES: [&str; 3] = [
+    "application/pkcs7-signature",
+    "application/x-pkcs7-signature",
+    "application/pgp-signature",
+];
+
+fn is_signature_part(part: &ParsedMail) -> bool {
+    SIGNATURE_MIMETYPES.contains(&part.ctype.mimetype.as_str())
+}
+
+/// Which leaf part of a multipart email [`extract_email_body_with_preference`] returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BodyPreference {
+    /// The first `text/html` part, falling back to `text/plain`, then to the first part.
+    #[default]
+    PreferHtml,
+    /// The first `text/plain` part, falling back to `text/html`, then to the first part.
+    PreferPlain,
+    /// The first part, whatever its type.
+    FirstLeaf,
+}
+
+pub fn extract_email_body(parsed_email: &ParsedMail) -> Result<Vec<u8>> {
+    extract_email_body_with_preference(parsed_email, BodyPreference::PreferHtml)
+}
+
+/// Returns the body of the leaf part selected by `preference`, searching nested multiparts
+/// depth-first.
+///
+/// Detached signature parts are never selected, so `multipart/signed` resolves to its content.
+/// A part whose body fails to decode is skipped in favor of the next candidate, and an email
+/// without any other leaf part returns its own body. Errors only if no candidate decodes.
+pub fn extract_email_body_with_preference(
+    parsed_email: &ParsedMail,
+    preference: BodyPreference,
+) -> Result<Vec<u8>> {
+    let mut leaves = Vec::new();
+    collect_body_leaves(parsed_email, &mut leaves);
+
+    let by_type = |mimetype: &'static str| {
+        leaves
+            .iter()
+            .copied()
+            .filter(move |part| part.ctype.mimetype == mimetype)
+    };
+    let preferred: Vec<&ParsedMail> = match preference {
+        BodyPreference::PreferHtml => by_type("text/html").chain(by_type("text/plain")).collect(),
+        BodyPreference::PreferPlain => by_type("text/plain").chain(by_type("text/html")).collect(),
+        BodyPreference::FirstLeaf => Vec::new(),
+    };
+
+    preferred
+        .into_iter()
+        .chain(leaves.iter().copied())
+        .chain(std::iter::once(parsed_email))
+        .find_map(|part| part.get_body_raw().ok())
+        .ok_or_else(|| anyhow!("No body part of the email could be decoded"))
+}
+
+fn collect_body_leaves<'a, 'b>(part: &'b ParsedMail<'a>, leaves: &mut Vec<&'b ParsedMail<'a>>) {
+    if part.subparts.is_empty() {
+        if !is_signature_part(part) {
+            leaves.push(part);
+        }
+        return;
+    }
+    for subpart in &part.subparts {
+        collect_body_leaves(subpart, leaves);
+    }
+}
+
+/// Applies [`extract_email_body`] to each email, in parallel for larger batches with the
+/// `parallel` feature. An email whose body cannot be decoded yields an empty body.
+pub fn extract_email_bodies_batch(emails: &[&ParsedMail]) -> Vec<Vec<u8>> {
+    let extract = |email: &&ParsedMail| extract_email_body(email).unwrap_or_default();
+
+    #[cfg(feature = "parallel")]
+    if emails.len() >= PARALLEL_BATCH_THRESHOLD {
+        return emails.par_iter().map(extract).collect();
+    }
+
+    emails.iter().map(extract).collect()
+}
+
+/// Options of the `_with_options` variants of the verification functions, such as
+/// [`verify_dkim_detail_with_options`]. The default leaves the raw email untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DkimOptions {
+    /// Converts bare LF line endings of the raw email to CRLF with [`normalize_line_endings`]
+    /// before verifying, for emails whose line endings were rewritten after signing.
+    pub normalize_line_endings: bool,
 }
 
 pub fn verify_dkim(input: &Email, logger: &Logger) -> bool {
-    let parsed_email = parse_mail(&input.raw_email).unwrap();
+    verify_dkim_detail(input, logger)
+        .unwrap()
+        .starts_with("pass")
+}
+
+/// Verifies the DKIM signature of each email independently, in parallel with the `parallel`
+/// feature.
+///
+/// An email that cannot be parsed or verified, or that makes the verifier panic, yields `false`
+/// without affecting the others.
+pub fn verify_dkim_batch(emails: &[&Email], logger: &Logger) -> Vec<bool> {
+    verify_batch_isolated(emails, |email| verify_dkim_detail(email, logger))
+}
+
+/// Runs `verify_detail` on each email, mapping errors and panics to `false`.
+fn verify_batch_isolated<F>(emails: &[&Email], verify_detail: F) -> Vec<bool>
+where
+    F: Fn(&Email) -> Result<String> + Sync,
+{
+    let verify = |email: &&Email| {
+        // cfdkim panics on some malformed input, which must not unwind across the whole batch
+        panic::catch_unwind(AssertUnwindSafe(|| verify_detail(email)))
+            .is_ok_and(|detail| detail.is_ok_and(|detail| detail.starts_with("pass")))
+    };
+
+    #[cfg(feature = "parallel")]
+    let results = emails.par_iter().map(verify).collect();
+    #[cfg(not(feature = "parallel"))]
+    let results = emails.iter().map(verify).collect();
+
+    results
+}
+
+/// Runs DKIM verification and returns the result's detail string, e.g. `pass` or
+/// `fail (...)`, without panicking on malformed input.
+pub fn verify_dkim_detail(input: &Email, logger: &Logger) -> Result<String> {
+    verify_dkim_detail_with_options(input, logger, DkimOptions::default())
+}
+
+/// Same as [`verify_dkim_detail`], applying `options` to the raw email first.
+pub fn verify_dkim_detail_with_options(
+    input: &Email,
+    logger: &Logger,
+    options: DkimOptions,
+) -> Result<String> {
+    let raw_email = apply_options(&input.raw_email, options);
+    let candidates: Vec<usize> = (0..count_dkim_signatures(&raw_email)).collect();
+    let (detail, _) = verify_dkim_signatures(
+        &raw_email,
+        &input.from_domain,
+        &input.public_key,
+        logger,
+        false,
+        &candidates,
+    )?;
+    Ok(detail)
+}
 
-    let public_key =
-        DkimPublicKey::try_from_bytes(&input.public_key.key, &input.public_key.key_type).unwrap();
+/// Verifies the DKIM signatures at the `candidates` indices, in order, and returns the detail of
+/// the first one that passes along with the raw email stripped of every other DKIM-Signature
+/// header, so that functions reading the first signature of an email read the verified one.
+///
+/// cfdkim stops at the first passing signature without telling which one it was, so each
+/// candidate is verified on its own with [`retain_dkim_signatures`]. If none passes, the detail
+/// cfdkim reports for the email with all candidates is returned, without an email.
+pub(crate) fn verify_dkim_signatures(
+    raw_email: &[u8],
+    from_domain: &str,
+    public_key: &PublicKey,
+    logger: &Logger,
+    ignore_body_hash: bool,
+    candidates: &[usize],
+) -> Result<(String, Option<Vec<u8>>)> {
+    for &index in candidates {
+        let isolated = retain_dkim_signatures(raw_email, |i| i == index);
+        match dkim_detail(&isolated, from_domain, public_key, logger, ignore_body_hash) {
+            Ok(detail) if detail.starts_with("pass") => return Ok((detail, Some(isolated))),
+            _ => {}
+        }
+    }
+
+    let retained = retain_dkim_signatures(raw_email, |i| candidates.contains(&i));
+    let detail = dkim_detail(&retained, from_domain, public_key, logger, ignore_body_hash)?;
+    Ok((detail, None))
+}
+
+/// Runs cfdkim on a raw email and returns the detail string of its result.
+fn dkim_detail(
+    raw_email: &[u8],
+    from_domain: &str,
+    public_key: &PublicKey,
+    logger: &Logger,
+    ignore_body_hash: bool,
+) -> Result<String> {
+    let parsed_email = parse_mail(raw_email)?;
+    let public_key = DkimPublicKey::try_from_bytes(&public_key.key, &public_key.key_type)?;
+    let result = verify_email_with_key(
+        logger,
+        from_domain,
+        &parsed_email,
+        public_key,
+        ignore_body_hash,
+    )?;
+    Ok(result.with_detail())
+}
+
+/// The result of a DKIM verification (RFC 8601 section 2.7.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DkimStatus {
+    Pass,
+    /// The signature or the body hash did not verify.
+    Fail,
+    Neutral,
+    /// A transient error, e.g. a key lookup failure; retrying may succeed.
+    TempError,
+    /// The email or its signature is malformed, or the key cannot be used.
+    PermError,
+}
+
+impl DkimStatus {
+    /// Parses the leading keyword of a cfdkim detail string such as `fail (body hash mismatch)`.
+    /// Unknown keywords are treated as [`DkimStatus::PermError`].
+    pub fn from_detail(detail: &str) -> Self {
+        let keyword = detail.split_whitespace().next().unwrap_or_default();
+        match keyword.to_ascii_lowercase().as_str() {
+            "pass" => Self::Pass,
+            "fail" => Self::Fail,
+            "neutral" => Self::Neutral,
+            "temperror" => Self::TempError,
+            _ => Self::PermError,
+        }
+    }
+}
+
+/// A [`DkimStatus`] together with the detail it was derived from, as returned by
+/// [`verify_dkim_outcome`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimOutcome {
+    pub status: DkimStatus,
+    pub detail: String,
+}
+
+impl DkimOutcome {
+    pub fn is_pass(&self) -> bool {
+        self.status == DkimStatus::Pass
+    }
+}
+
+/// Same as [`verify_dkim_detail`], classifying the detail string so that callers can tell a
+/// failed verification apart from a neutral or erroneous one.
+///
+/// Input that cannot be parsed or verified at all yields [`DkimStatus::PermError`] with the
+/// error message as detail.
+pub fn verify_dkim_outcome(input: &Email, logger: &Logger) -> DkimOutcome {
+    match verify_dkim_detail(input, logger) {
+        Ok(detail) => DkimOutcome {
+            status: DkimStatus::from_detail(&detail),
+            detail,
+        },
+        Err(e) => DkimOutcome {
+            status: DkimStatus::PermError,
+            detail: e.to_string(),
+        },
+    }
+}
+
+/// Same as [`verify_dkim_detail`], failing unless the DKIM verification passes.
+///
+/// With `require_signed_from`, the email is also rejected with [`VerifyError::FromNotSigned`]
+/// when `From` is missing from the signature's `h=` tag, since an unsigned `From` makes the
+/// committed domain meaningless.
+///
+/// A signature whose `i=` tag is outside its `d=` domain is always rejected with
+/// [`VerifyError::InvalidAuid`], see [`validate_auid_scope`].
+///
+/// These checks apply to the signature that verifies: signatures failing them are skipped, and
+/// the error of the first signature is returned when they all fail.
+pub fn verify_dkim_checked(
+    input: &Email,
+    logger: &Logger,
+    require_signed_from: bool,
+) -> Result<String, VerifyError> {
+    verify_dkim_signature_checked(input, logger, require_signed_from).map(|(detail, _)| detail)
+}
+
+/// Same as [`verify_dkim_checked`], also returning the raw email stripped of every DKIM-Signature
+/// header but the one that verified, see [`verify_dkim_signatures`].
+pub(crate) fn verify_dkim_signature_checked(
+    input: &Email,
+    logger: &Logger,
+    require_signed_from: bool,
+) -> Result<(String, Vec<u8>), VerifyError> {
+    let invalid = |e: anyhow::Error| VerifyError::InvalidInput(e.to_string());
+    let check = |fields: &HashMap<String, String>| {
+        validate_auid_scope(fields).map_err(VerifyError::InvalidAuid)?;
+        if require_signed_from && !requires_header(fields, "From") {
+            return Err(VerifyError::FromNotSigned);
+        }
+        Ok(())
+    };
+
+    let signatures = extract_all_dkim_fields(&input.raw_email).map_err(invalid)?;
+    let first = signatures
+        .first()
+        .ok_or_else(|| VerifyError::InvalidInput("No DKIM signatures found".to_string()))?;
+    let candidates: Vec<usize> = (0..signatures.len())
+        .filter(|&index| check(&signatures[index]).is_ok())
+        .collect();
+    if candidates.is_empty() {
+        check(first)?;
+    }
+
+    let (detail, verified_email) = verify_dkim_signatures(
+        &input.raw_email,
+        &input.from_domain,
+        &input.public_key,
+        logger,
+        false,
+        &candidates,
+    )
+    .map_err(invalid)?;
+    if !detail.starts_with("pass") {
+        return Err(VerifyError::DkimFailed(detail));
+    }
+    // Only possible when signatures sign one another, so that none passes on its own
+    let verified_email = verified_email.ok_or_else(|| {
+        VerifyError::InvalidInput("Cannot tell which DKIM signature verified".to_string())
+    })?;
+    Ok((detail, verified_email))
+}
+
+/// Returns the lowercased mailbox address of the `From` header.
+///
+/// Emails with more than one `From` header are rejected: DKIM signs the bottom-most one, so a
+/// `From` prepended in transit would otherwise be returned in place of the signed one.
+pub fn extract_from_address(parsed_email: &ParsedMail) -> Result<String> {
+    let headers = parsed_email.headers.get_all_headers("From");
+    let header = match headers.as_slice() {
+        [header] => header,
+        [] => return Err(anyhow!("No From header found")),
+        _ => return Err(anyhow!("Email has {} From headers", headers.len())),
+    };
+
+    addrparse_header(header)?
+        .extract_single_info()
+        .map(|info| info.addr.to_lowercase())
+        .ok_or_else(|| anyhow!("From header must contain a single mailbox"))
+}
 
-    let result =
-        verify_email_with_key(logger, &input.from_domain, &parsed_email, public_key, false)
-            .unwrap();
+/// Hashes the `From` mailbox of a raw email as returned by [`extract_from_address`], i.e. the
+/// lowercased `local-part@domain` without its display name.
+///
+/// The hash does not prove anything unless `From` is signed; see
+/// [`crate::try_verify_email_with_from_address`].
+pub fn from_address_hash(raw_email: &[u8], version: CommitmentVersion) -> Result<Vec<u8>> {
+    let address = extract_from_address(&parse_mail(raw_email)?)?;
+    Ok(version.hash_from_address(address.as_bytes()))
+}
+
+/// Returns the lowercased domain of the `From` header's mailbox.
+pub fn extract_from_domain(parsed_email: &ParsedMail) -> Result<String> {
+    let address = extract_from_address(parsed_email)?;
+    address
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_string())
+        .ok_or_else(|| anyhow!("From address has no domain: {}", address))
+}
+
+/// Returns the bytes body regex parts are matched against for `target`, given the raw email and
+/// its body canonicalized for its DKIM signature.
+///
+/// [`MatchTarget::DecodedText`] is rejected for signatures with an `l=` tag, since the decoded
+/// text could then include unsigned content appended to the body, and for signatures whose `h=`
+/// tag does not cover `Content-Type` and `Content-Transfer-Encoding`, since the decoding depends
+/// on them.
+///
+/// The tags are read from the first DKIM signature of `raw_email`; pass the email stripped down
+/// to the signature that verifies it, as [`crate::regex_match_inputs`] does.
+pub fn body_match_input(
+    raw_email: &[u8],
+    canonicalized_body: Vec<u8>,
+    target: MatchTarget,
+) -> Result<Vec<u8>> {
+    match target {
+        MatchTarget::SignedWire => Ok(remove_quoted_printable_soft_breaks(canonicalized_body).0),
+        MatchTarget::DecodedText => {
+            let fields = extract_dkim_fields(raw_email)?;
+            if fields.contains_key("l") {
+                return Err(anyhow!(
+                    "Cannot match decoded text of a body signed with an l= tag"
+                ));
+            }
+            if let Some(name) = ["Content-Type", "Content-Transfer-Encoding"]
+                .into_iter()
+                .find(|name| !requires_header(&fields, name))
+            {
+                return Err(anyhow!(
+                    "Cannot match decoded text of a body whose {} header is not signed",
+                    name
+                ));
+            }
+            extract_email_body(&parse_mail(raw_email)?)
+        }
+    }
+}
+
+/// Returns the `Message-ID` of a raw email without its angle brackets, e.g. `abc@example.com`.
+pub fn extract_message_id(eml_content: &[u8]) -> Result<String> {
+    let (headers, _) = mailparse::parse_headers(eml_content)?;
+    let value = headers
+        .get_first_value("Message-ID")
+        .ok_or_else(|| anyhow!("No Message-ID header found"))?;
+
+    let value = value.trim();
+    let message_id = value
+        .strip_prefix('<')
+        .and_then(|id| id.strip_suffix('>'))
+        .unwrap_or(value)
+        .trim();
+    if message_id.is_empty() {
+        return Err(anyhow!("Message-ID header is empty"));
+    }
+    Ok(message_id.to_string())
+}
+
+/// Returns the `Date` header of a raw email, parsed as an RFC 5322 date.
+///
+/// Unlike [`crate::extract_signed_date_unix`], the date is returned whether or not it is signed.
+/// A malformed date is an error rather than a default.
+pub fn extract_date(eml_content: &[u8]) -> Result<DateTime<Utc>> {
+    let (headers, _) = mailparse::parse_headers(eml_content)?;
+    let value = headers
+        .get_first_value("Date")
+        .ok_or_else(|| anyhow!("No Date header found"))?;
+
+    let timestamp =
+        dateparse(&value).map_err(|e| anyhow!("Invalid Date header {:?}: {}", value, e))?;
+    DateTime::from_timestamp(timestamp, 0)
+        .ok_or_else(|| anyhow!("Date header {:?} is out of range", value))
+}
+
+/// Returns every header of a raw email as `(name, value)` pairs in header order.
+///
+/// Names keep their original case. Values are unfolded and RFC 2047 encoded words are decoded.
+pub fn parse_headers(eml_content: &[u8]) -> Result<Vec<(String, String)>> {
+    let (headers, _) = mailparse::parse_headers(eml_content)?;
+    Ok(headers
+        .iter()
+        .map(|header| (header.get_key(), header.get_value()))
+        .collect())
+}
+
+/// Groups the headers of a raw email by name, keeping every value of a repeated header in
+/// header order.
+///
+/// Keys are lowercased so lookups are case-insensitive, e.g. `map["subject"]`; values keep their
+/// original case.
+pub fn header_map(eml_content: &[u8]) -> Result<HashMap<String, Vec<String>>> {
+    let mut map: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in parse_headers(eml_content)? {
+        map.entry(name.to_lowercase()).or_default().push(value);
+    }
+    Ok(map)
+}
+
+/// An email parsed by [`parse_email_safe`], owning everything it extracted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedEmail {
+    /// The header fields in order, with decoded values.
+    pub headers: Vec<(String, String)>,
+    /// The lowercased `From` mailbox, if the header holds exactly one.
+    pub from_address: Option<String>,
+    /// The body as returned by [`extract_email_body`].
+    pub body: Vec<u8>,
+    /// The tags of each DKIM-Signature header, in header order. Nothing is verified.
+    pub dkim_signatures: Vec<HashMap<String, String>>,
+}
+
+/// Why [`parse_email_safe`] rejected an email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The header block or the MIME structure could not be parsed.
+    Malformed(String),
+    /// No body part could be decoded.
+    UndecodableBody(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(e) => write!(f, "Malformed email: {}", e),
+            Self::UndecodableBody(e) => write!(f, "Undecodable email body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses an untrusted email into its headers, `From` address, body and DKIM-Signature tags.
+///
+/// This is the recommended entry point for untrusted input, and a suitable fuzz target: every
+/// failure is returned as a [`ParseError`] and it never panics, unlike the verification
+/// functions that assume well-formed input.
+pub fn parse_email_safe(eml_content: &[u8]) -> Result<ParsedEmail, ParseError> {
+    let parsed_email = parse_mail(eml_content).map_err(|e| ParseError::Malformed(e.to_string()))?;
+    let body = extract_email_body(&parsed_email)
+        .map_err(|e| ParseError::UndecodableBody(e.to_string()))?;
+
+    Ok(ParsedEmail {
+        headers: parsed_email
+            .headers
+            .iter()
+            .map(|header| (header.get_key(), header.get_value()))
+            .collect(),
+        from_address: extract_from_address(&parsed_email).ok(),
+        body,
+        dkim_signatures: parsed_email
+            .headers
+            .get_all_headers("DKIM-Signature")
+            .iter()
+            .map(|header| parse_dkim_tags(&String::from_utf8_lossy(header.get_value_raw())))
+            .collect(),
+    })
+}
+
+/// Checks a canonicalized body against the base64 `bh=` value of a DKIM signature.
+pub fn verify_body(body: &[u8], body_hash: &str) -> Result<bool> {
+    verify_body_with_length(body, body_hash, None)
+}
+
+/// Checks a canonicalized body against the base64 `bh=` value, honoring the `l=` body length tag.
+///
+/// When `length` is set only the first `length` octets of the canonicalized body are hashed, as
+/// required by RFC 6376 section 3.5. A length longer than the body is clamped to the body length.
+///
+/// # Arguments
+///
+/// * `body` - The body after DKIM canonicalization.
+/// * `body_hash` - The base64 `bh=` value; folding whitespace is ignored.
+/// * `length` - The parsed `l=` tag, if present.
+///
+/// # Returns
+///
+/// `true` if the SHA-256 of the signed portion of the body matches `body_hash`.
+pub fn verify_body_with_length(
+    body: &[u8],
+    body_hash: &str,
+    length: Option<usize>,
+) -> Result<bool> {
+    verify_body_with_hash(body, body_hash, length, HashAlgorithm::Sha256)
+}
+
+/// Same as [`verify_body_with_length`], hashing the body with the hash function of the
+/// signature's `a=` algorithm.
+pub fn verify_body_with_hash(
+    body: &[u8],
+    body_hash: &str,
+    length: Option<usize>,
+    hash: HashAlgorithm,
+) -> Result<bool> {
+    let expected = STANDARD.decode(body_hash.split_whitespace().collect::<String>())?;
+    let signed = length.map_or(body, |len| &body[..len.min(body.len())]);
+    Ok(hash.digest(signed) == expected)
+}
+
+/// Same as [`verify_body_with_length`], taking the `bh=`, `l=` and `a=` tags from the
+/// DKIM-Signature fields and hashing the body with the hash function named by `a=`.
+///
+/// Errors if `a=` or `bh=` is missing or if `a=` names an unsupported hash, such as the
+/// obsolete `rsa-sha1`, instead of reporting a mismatch.
+pub fn verify_body_auto(body: &[u8], dkim_fields: &HashMap<String, String>) -> Result<bool> {
+    let tag = |name: &str| {
+        dkim_fields
+            .get(name)
+            .ok_or_else(|| anyhow!("DKIM-Signature is missing the {}= tag", name))
+    };
+    let hash = signature_hash(dkim_fields)?;
+    let length = dkim_fields
+        .get("l")
+        .map(|length| length.parse::<usize>())
+        .transpose()?;
+    verify_body_with_hash(body, tag("bh")?, length, hash)
+}
+
+/// Returns the byte range of the value of the first `name` header in a header block, such as
+/// the canonicalized header of a DKIM signature. The range starts after the colon and excludes
+/// the terminating CRLF; folded lines are included.
+pub fn header_value_range(header: &[u8], name: &str) -> Option<Range<usize>> {
+    let mut start = 0;
+    while start < header.len() {
+        // A field ends at the first line break not followed by folding whitespace
+        let mut end = start;
+        loop {
+            match header[end..].iter().position(|&byte| byte == b'\n') {
+                Some(pos) => {
+                    end += pos + 1;
+                    if !matches!(header.get(end), Some(b' ' | b'\t')) {
+                        break;
+                    }
+                }
+                None => {
+                    end = header.len();
+                    break;
+                }
+            }
+        }
+
+        let field = &header[start..end];
+        if let Some(colon) = field.iter().position(|&byte| byte == b':') {
+            if String::from_utf8_lossy(&field[..colon])
+                .trim()
+                .eq_ignore_ascii_case(name)
+            {
+                let value_end = if field.ends_with(b"\r\n") {
+                    end - 2
+                } else if field.ends_with(b"\n") {
+                    end - 1
+                } else {
+                    end
+                };
+                return Some(start + colon + 1..value_end);
+            }
+        }
+        start = end;
+    }
+    None
+}
+
+/// Returns the header block of a raw email as it appears on the wire, up to and including the
+/// line break of its last field. Without a blank line the whole input is the header block.
+pub fn raw_header(raw_email: &[u8]) -> &[u8] {
+    let blank_line = (1..raw_email.len()).find_map(|i| match &raw_email[i..] {
+        [b'\n', b'\r', b'\n', ..] => Some(i + 1),
+        [b'\n', b'\n', ..] => Some(i + 1),
+        _ => None,
+    });
+    &raw_email[..blank_line.unwrap_or(raw_email.len())]
+}
+
+/// Unfolds header lines per RFC 5322 by removing every line break (CRLF or bare LF) that is
+/// followed by a space or tab. The whitespace itself is kept, so each fold becomes a single
+/// space or tab. Line breaks ending a field, including a final one, are left as they are.
+pub fn unfold_header(raw: &[u8]) -> Vec<u8> {
+    let mut unfolded = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        let line_break = match &raw[i..] {
+            [b'\r', b'\n', ..] => 2,
+            [b'\n', ..] => 1,
+            _ => 0,
+        };
+        if line_break > 0 && matches!(raw.get(i + line_break), Some(b' ' | b'\t')) {
+            i += line_break;
+        } else {
+            unfolded.push(raw[i]);
+            i += 1;
+        }
+    }
+    unfolded
+}
+
+/// Formats `name: value` as a header field folded so that lines are at most `max_width` bytes
+/// where possible, without a trailing CRLF.
+///
+/// Lines are only broken before existing whitespace in `value`, which starts the continuation
+/// line, so [`unfold_header`] restores the unfolded field exactly. A word longer than
+/// `max_width` is left on its own overlong line.
+pub fn fold_header(name: &str, value: &str, max_width: usize) -> String {
+    let field = format!("{}: {}", name, value.trim_start());
+    let breaks: Vec<usize> = field
+        .char_indices()
+        .filter(|&(i, c)| i > name.len() + 1 && (c == ' ' || c == '\t'))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    while field.len() - line_start > max_width {
+        let mut candidates = breaks.iter().filter(|&&b| b > line_start);
+        let fits = candidates
+            .clone()
+            .rev()
+            .find(|&&b| b - line_start <= max_width);
+        let Some(&line_end) = fits.or(candidates.next()) else {
+            break;
+        };
+        lines.push(&field[line_start..line_end]);
+        line_start = line_end;
+    }
+    lines.push(&field[line_start..]);
+    lines.join("\r\n")
+}
+
+/// Computes the base64 `bh=` body hash of the first DKIM signature of a raw email.
+///
+/// The body is canonicalized as declared by the signature's `c=` tag, truncated to its `l=` tag,
+/// if any, and hashed with the hash function of its `a=` tag. Quoted-printable soft line breaks
+/// are part of the signed body, so they are not removed.
+pub fn compute_body_hash(raw_email: &[u8]) -> Result<String> {
+    compute_body_hash_with_options(raw_email, DkimOptions::default())
+}
 
-    result.with_detail().starts_with("pass")
+/// Same as [`compute_body_hash`], applying `options` to the raw email first.
+pub fn compute_body_hash_with_options(raw_email: &[u8], options: DkimOptions) -> Result<String> {
+    let (fields, body) = first_signed_body(raw_email, options)?;
+    Ok(STANDARD.encode(signature_hash(&fields)?.digest(&body)))
+}
+
+/// Checks the body of a raw email against the `bh=` tag of its first DKIM signature.
+///
+/// Neither the signature nor the signing key are checked, so no DNS lookup is needed. This makes
+/// it a cheap way to tell whether a body was modified in transit before fetching any key.
+pub fn verify_body_hash_only(eml_content: &[u8]) -> Result<bool> {
+    verify_body_hash_only_with_options(eml_content, DkimOptions::default())
+}
+
+/// Same as [`verify_body_hash_only`], applying `options` to the raw email first.
+pub fn verify_body_hash_only_with_options(
+    eml_content: &[u8],
+    options: DkimOptions,
+) -> Result<bool> {
+    let eml_content = apply_options(eml_content, options);
+    let fields = extract_dkim_fields(&eml_content)?;
+    let body_hash = fields
+        .get("bh")
+        .ok_or_else(|| anyhow!("DKIM-Signature is missing the bh= tag"))?;
+    Ok(compute_body_hash(&eml_content)? == body_hash.split_whitespace().collect::<String>())
+}
+
+/// Same as [`verify_body`], returning both hashes instead of a bare `bool`.
+pub fn verify_body_detailed(body: &[u8], expected_bh: &str) -> Result<BodyHashReport> {
+    let expected_hash: String = expected_bh.split_whitespace().collect();
+    let expected = STANDARD.decode(&expected_hash)?;
+    let computed = hash_bytes(body);
+
+    Ok(BodyHashReport {
+        computed_hash: STANDARD.encode(&computed),
+        expected_hash,
+        matches: computed == expected,
+        first_difference: None,
+    })
+}
+
+/// Same as [`verify_body_detailed`], also locating the first byte at which `body` differs from
+/// `reference`, e.g. the canonicalized body of the email before it was transformed.
+pub fn verify_body_detailed_against(
+    body: &[u8],
+    expected_bh: &str,
+    reference: &[u8],
+) -> Result<BodyHashReport> {
+    let mut report = verify_body_detailed(body, expected_bh)?;
+    report.first_difference = first_difference(body, reference);
+    Ok(report)
+}
+
+/// Returns the first offset at which `a` and `b` differ, or `None` if they are equal. When one is
+/// a prefix of the other, the offset is the length of the shorter one.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then_some(a.len().min(b.len())))
+}
+
+/// Converts bare LF line endings of a raw email, or of any part of one, to CRLF, leaving
+/// existing CRLFs untouched, since DKIM canonicalization operates on CRLF lines.
+pub fn normalize_line_endings(raw_email: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(raw_email.len());
+    for (i, &byte) in raw_email.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || raw_email[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+    }
+    converted
+}
+
+/// Canonicalizes a body with the DKIM "simple" algorithm (RFC 6376 section 3.4.3).
+///
+/// Trailing empty lines are removed and the body is terminated by a single CRLF, so an empty body
+/// canonicalizes to `\r\n`.
+pub fn canonicalize_body_simple(body: &[u8]) -> Vec<u8> {
+    let mut canonicalized = body.to_vec();
+    while canonicalized.ends_with(b"\r\n") {
+        canonicalized.truncate(canonicalized.len() - 2);
+    }
+    canonicalized.extend_from_slice(b"\r\n");
+    canonicalized
+}
+
+/// Canonicalizes a body with the DKIM "relaxed" algorithm (RFC 6376 section 3.4.4).
+///
+/// Whitespace runs within a line are reduced to a single space, whitespace at the end of lines
+/// and empty lines at the end of the body are removed, and every remaining line is terminated by
+/// CRLF. An empty body canonicalizes to an empty body.
+pub fn canonicalize_body_relaxed(body: &[u8]) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = split_crlf_lines(body)
+        .into_iter()
+        .map(|line| {
+            let mut canonicalized = Vec::with_capacity(line.len());
+            let mut pending_space = false;
+            for &byte in line {
+                if byte == b' ' || byte == b'\t' {
+                    pending_space = true;
+                } else {
+                    if pending_space {
+                        canonicalized.push(b' ');
+                        pending_space = false;
+                    }
+                    canonicalized.push(byte);
+                }
+            }
+            canonicalized
+        })
+        .collect();
+
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    lines
+        .iter()
+        .flat_map(|line| line.iter().copied().chain(*b"\r\n"))
+        .collect()
+}
+
+/// Splits a body on CRLF. A final line without a trailing CRLF is still returned.
+fn split_crlf_lines(body: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut rest = body;
+    while let Some(pos) = rest.windows(2).position(|window| window == b"\r\n") {
+        lines.push(&rest[..pos]);
+        rest = &rest[pos + 2..];
+    }
+    if !rest.is_empty() {
+        lines.push(rest);
+    }
+    lines
 }
 
 // TODO: remove this when using relayer-utils
-/// Removes Quoted-Printable (QP) soft line breaks (`=\r\n`) from the given byte vector while
-/// maintaining a mapping from cleaned indices back to the original positions.
+/// Removes Quoted-Printable (QP) soft line breaks (`=\r\n`, or `=\n` as emitted by mailers
+/// using bare LF line endings) from the given byte vector while maintaining a mapping from
+/// cleaned indices back to the original positions.
 ///
 /// Quoted-printable encoding may split long lines with `=\r\n` sequences. This function removes
 /// these soft line breaks, producing a "cleaned" output array. It also creates an index map so
 /// that for each position in the cleaned output, you can find the corresponding original index.
+/// A `=` at the very end of the input is not followed by a line break and is kept.
 ///
 /// Any positions in the cleaned output that were added as padding (to match the original length)
 /// will have their index map entry set to `usize::MAX`, indicating no corresponding original index.
@@ -65,10 +893,12 @@ pub fn remove_quoted_printable_soft_breaks(body: Vec<u8>) -> (Vec<u8>, Vec<usize
 
     let mut iter = body.iter().enumerate();
     while let Some((i, &byte)) = iter.next() {
-        // Check if this is the start of a soft line break sequence `=\r\n`
+        // Check if this is the start of a soft line break sequence `=\r\n` or `=\n`
         if byte == b'=' && body.get(i + 1..i + 3) == Some(b"\r\n") {
             // Skip the next two bytes for the soft line break
             iter.nth(1);
+        } else if byte == b'=' && body.get(i + 1) == Some(&b'\n') {
+            iter.next();
         } else {
             cleaned.push(byte);
             index_map.push(i);
@@ -84,3 +914,690 @@ pub fn remove_quoted_printable_soft_breaks(body: Vec<u8>) -> (Vec<u8>, Vec<usize
 
     (cleaned, index_map)
 }
+
+/// Inverts the index map returned by [`remove_quoted_printable_soft_breaks`], mapping each of the
+/// `original_len` original offsets to its position in the cleaned buffer.
+///
+/// Offsets of removed soft line break bytes map to `None`. Padding entries (`usize::MAX`) and
+/// entries past `original_len` are ignored.
+pub fn build_reverse_index_map(index_map: &[usize], original_len: usize) -> Vec<Option<usize>> {
+    let mut reverse = vec![None; original_len];
+    for (cleaned, &original) in index_map.iter().enumerate() {
+        if let Some(slot) = reverse.get_mut(original) {
+            *slot = Some(cleaned);
+        }
+    }
+    reverse
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::{
+        pkcs1::{DecodeRsaPrivateKey, EncodeRsaPublicKey},
+        RsaPrivateKey,
+    };
+
+    use super::*;
+    use crate::{sign_dkim, TEST_PRIVATE_KEY};
+
+    const UNSIGNED_EMAIL: &[u8] = b"From: Alice <alice@example.com>\r\n\
+To: bob@example.org\r\n\
+Subject: Hello\r\n\
+\r\n\
+Hi Bob,\r\n";
+
+    /// Signs [`UNSIGNED_EMAIL`] with `TEST_PRIVATE_KEY` and the DKIM tags `tags`.
+    fn dkim_signed_email(tags: &str) -> Email {
+        let private_key = RsaPrivateKey::from_pkcs1_pem(TEST_PRIVATE_KEY).unwrap();
+        let public_key = private_key.to_public_key().to_pkcs1_der().unwrap();
+        Email {
+            from_domain: "example.com".to_string(),
+            raw_email: sign_dkim(UNSIGNED_EMAIL, tags, &private_key).unwrap(),
+            public_key: PublicKey {
+                key: public_key.as_bytes().to_vec(),
+                key_type: "rsa".to_string(),
+            },
+            external_inputs: Vec::new(),
+        }
+    }
+
+    const SIGNED_EMAIL: &[u8] = b"Content-Type: multipart/signed; \
+protocol=\"application/pkcs7-signature\"; micalg=sha-256; boundary=\"sig\"\r\n\
+\r\n\
+--sig\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Signed content\r\n\
+--sig\r\n\
+Content-Type: application/pkcs7-signature; name=\"smime.p7s\"\r\n\
+\r\n\
+SIGNATURE\r\n\
+--sig--\r\n";
+
+    const NESTED_EMAIL: &[u8] = b"Content-Type: multipart/mixed; boundary=\"mixed\"\r\n\
+\r\n\
+--mixed\r\n\
+Content-Type: multipart/alternative; boundary=\"alt\"\r\n\
+\r\n\
+--alt\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Plain body\r\n\
+--alt\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>HTML body</p>\r\n\
+--alt--\r\n\
+--mixed\r\n\
+Content-Type: application/pdf\r\n\
+\r\n\
+PDF\r\n\
+--mixed--\r\n";
+
+    const EMPTY_BODY_HASH: &str = "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY=";
+
+    #[test]
+    fn test_extract_email_body_multipart_signed() {
+        let parsed = parse_mail(SIGNED_EMAIL).unwrap();
+        let body = extract_email_body(&parsed).unwrap();
+        assert!(body.starts_with(b"Signed content"));
+        assert!(!body.windows(9).any(|w| w == b"SIGNATURE"));
+    }
+
+    #[test]
+    fn test_extract_email_body_with_preference_nested() {
+        let parsed = parse_mail(NESTED_EMAIL).unwrap();
+        let body = |preference| extract_email_body_with_preference(&parsed, preference).unwrap();
+
+        assert!(body(BodyPreference::PreferHtml).starts_with(b"<p>HTML body</p>"));
+        assert!(body(BodyPreference::PreferPlain).starts_with(b"Plain body"));
+        assert!(body(BodyPreference::FirstLeaf).starts_with(b"Plain body"));
+        assert_eq!(
+            extract_email_body(&parsed).unwrap(),
+            body(BodyPreference::PreferHtml)
+        );
+    }
+
+    #[test]
+    fn test_extract_email_body_skips_undecodable_part() {
+        let email = b"Content-Type: multipart/alternative; boundary=\"alt\"\r\n\
+\r\n\
+--alt\r\n\
+Content-Type: text/html\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+@@@ not base64 @@@\r\n\
+--alt\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Plain body\r\n\
+--alt--\r\n";
+        let parsed = parse_mail(email).unwrap();
+        assert!(extract_email_body(&parsed)
+            .unwrap()
+            .starts_with(b"Plain body"));
+    }
+
+    #[test]
+    fn test_verify_dkim_checked_requires_signed_from() {
+        let email = Email {
+            from_domain: "example.com".to_string(),
+            raw_email:
+                b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1; h=subject; bh=; b=\r\n\
+From: alice@example.com\r\n\
+Subject: Hi\r\n\
+\r\n"
+                    .to_vec(),
+            public_key: crate::PublicKey {
+                key: Vec::new(),
+                key_type: "rsa".to_string(),
+            },
+            external_inputs: Vec::new(),
+        };
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        assert_eq!(
+            verify_dkim_checked(&email, &logger, true),
+            Err(VerifyError::FromNotSigned)
+        );
+        assert_ne!(
+            verify_dkim_checked(&email, &logger, false),
+            Err(VerifyError::FromNotSigned)
+        );
+    }
+
+    #[test]
+    fn test_verify_dkim_checked_rejects_foreign_auid() {
+        let email = Email {
+            from_domain: "example.com".to_string(),
+            raw_email:
+                b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; i=alice@evil.example;\r\n\
+\ts=s1; h=from; bh=; b=\r\n\
+From: alice@example.com\r\n\
+\r\n"
+                    .to_vec(),
+            public_key: crate::PublicKey {
+                key: Vec::new(),
+                key_type: "rsa".to_string(),
+            },
+            external_inputs: Vec::new(),
+        };
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        assert_eq!(
+            verify_dkim_checked(&email, &logger, false),
+            Err(VerifyError::InvalidAuid(crate::AuidError::OutOfScope {
+                auid_domain: "evil.example".to_string(),
+                domain: "example.com".to_string(),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_verify_dkim_checked_validates_verified_signature() {
+        let mut email = dkim_signed_email(
+            "v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1; h=from:subject",
+        );
+        let logger = Logger::root(slog::Discard, slog::o!());
+        assert!(verify_dkim_checked(&email, &logger, false).is_ok());
+
+        // A signature above the one that verifies must not be checked in its place
+        email.raw_email = [
+            b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; i=alice@evil.example;\r\n\
+\ts=s2; h=from; bh=; b=\r\n"
+                .as_slice(),
+            &email.raw_email,
+        ]
+        .concat();
+        assert!(verify_dkim_checked(&email, &logger, false).is_ok());
+    }
+
+    #[test]
+    fn test_verify_dkim_checked_requires_from_in_verified_signature() {
+        let mut email = dkim_signed_email(
+            "v=1; a=rsa-sha256; c=simple/simple; d=example.com; s=s1; h=from:to:subject",
+        );
+        email.raw_email = [
+            b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s2; h=subject; bh=; b=\r\n"
+                .as_slice(),
+            &email.raw_email,
+        ]
+        .concat();
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        assert!(verify_dkim_checked(&email, &logger, true).is_ok());
+    }
+
+    #[test]
+    fn test_header_value_range() {
+        let header =
+            b"from:alice@example.com\r\nSubject: Hello\r\n world\r\ndkim-signature:v=1; b=";
+        let value = |name| header_value_range(header, name).map(|range| &header[range]);
+
+        assert_eq!(value("subject"), Some(&b" Hello\r\n world"[..]));
+        assert_eq!(value("DKIM-Signature"), Some(&b"v=1; b="[..]));
+        assert_eq!(value("To"), None);
+    }
+
+    #[test]
+    fn test_body_match_input() {
+        let email = b"DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=s1;\r\n\
+\th=from:content-type:content-transfer-encoding; bh=; b=\r\n\
+From: alice@example.com\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+VG90YWw6ICQ0Mg==\r\n";
+        let body = b"VG90YWw6ICQ0Mg==\r\n".to_vec();
+
+        assert_eq!(
+            body_match_input(email, body.clone(), MatchTarget::SignedWire).unwrap(),
+            body
+        );
+        assert_eq!(
+            body_match_input(email, body.clone(), MatchTarget::DecodedText).unwrap(),
+            b"Total: $42"
+        );
+
+        let truncated = String::from_utf8_lossy(email).replace("b=\r\n", "l=10; b=\r\n");
+        assert!(
+            body_match_input(truncated.as_bytes(), body.clone(), MatchTarget::DecodedText).is_err()
+        );
+
+        // A Content-Transfer-Encoding added in transit would change the decoded text
+        let unsigned_encoding =
+            String::from_utf8_lossy(email).replace(":content-transfer-encoding", "");
+        assert!(body_match_input(
+            unsigned_encoding.as_bytes(),
+            body.clone(),
+            MatchTarget::SignedWire
+        )
+        .is_ok());
+        assert!(
+            body_match_input(unsigned_encoding.as_bytes(), body, MatchTarget::DecodedText).is_err()
+        );
+    }
+
+    #[test]
+    fn test_from_address_hash() {
+        let email = b"From: \"Alice (Sales)\" <Alice.Smith@Example.COM>\r\n\r\nHi";
+        assert_eq!(
+            from_address_hash(email, CommitmentVersion::V1).unwrap(),
+            hash_bytes(b"alice.smith@example.com")
+        );
+        assert!(
+            from_address_hash(b"To: bob@example.com\r\n\r\nHi", CommitmentVersion::V1).is_err()
+        );
+
+        let prepended = b"From: ceo@victim.example\r\nFrom: alice@example.com\r\n\r\nHi";
+        assert!(from_address_hash(prepended, CommitmentVersion::V1).is_err());
+    }
+
+    #[test]
+    fn test_extract_message_id_and_date() {
+        let email = b"Message-ID: <abc.123@mail.example.com>\r\n\
+Date: Tue, 2 Jan 2024 10:30:00 +0100\r\n\
+\r\n\
+Body";
+        assert_eq!(
+            extract_message_id(email).unwrap(),
+            "abc.123@mail.example.com"
+        );
+        assert_eq!(
+            extract_date(email).unwrap().to_rfc3339(),
+            "2024-01-02T09:30:00+00:00"
+        );
+
+        assert!(extract_message_id(b"Date: Tue, 2 Jan 2024 10:30:00 +0100\r\n\r\n").is_err());
+        assert!(extract_message_id(b"Message-ID: <>\r\n\r\n").is_err());
+        assert!(extract_date(b"Message-ID: <a@b>\r\n\r\n").is_err());
+        assert!(extract_date(b"Date: yesterday\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_header_map() {
+        let email = b"Received: from a\r\nSubject: Hello\r\n World\r\nreceived: from b\r\n\r\nBody";
+
+        assert_eq!(
+            parse_headers(email).unwrap(),
+            vec![
+                ("Received".to_string(), "from a".to_string()),
+                ("Subject".to_string(), "Hello World".to_string()),
+                ("received".to_string(), "from b".to_string()),
+            ]
+        );
+
+        let map = header_map(email).unwrap();
+        assert_eq!(map["received"], vec!["from a", "from b"]);
+        assert_eq!(map["subject"], vec!["Hello World"]);
+        assert!(!map.contains_key("Subject"));
+    }
+
+    #[test]
+    fn test_raw_header() {
+        assert_eq!(
+            raw_header(b"From: a\r\nSubject: Hi\r\n\r\nBody\r\n"),
+            b"From: a\r\nSubject: Hi\r\n"
+        );
+        assert_eq!(raw_header(b"From: a\n\nBody\n\n"), b"From: a\n");
+        assert_eq!(raw_header(b"From: a\r\n"), b"From: a\r\n");
+    }
+
+    #[test]
+    fn test_unfold_header() {
+        assert_eq!(
+            unfold_header(b"Subject: Hello\r\n world\r\n\tagain\r\nTo: bob\r\n"),
+            b"Subject: Hello world\tagain\r\nTo: bob\r\n"
+        );
+        assert_eq!(unfold_header(b"Subject: a\n b"), b"Subject: a b");
+        assert_eq!(unfold_header(b"Subject: no fold"), b"Subject: no fold");
+    }
+
+    #[test]
+    fn test_fold_header() {
+        let value = "a fairly long subject line that needs folding";
+        let folded = fold_header("Subject", value, 20);
+        assert_eq!(
+            folded,
+            "Subject: a fairly\r\n long subject line\r\n that needs folding"
+        );
+        assert!(folded.split("\r\n").all(|line| line.len() <= 20));
+        assert_eq!(
+            unfold_header(folded.as_bytes()),
+            format!("Subject: {}", value).as_bytes()
+        );
+
+        assert_eq!(
+            fold_header("To", "bob@example.com", 78),
+            "To: bob@example.com"
+        );
+        assert_eq!(
+            fold_header("X", "averyveryverylongword tail", 10),
+            "X: averyveryverylongword\r\n tail"
+        );
+    }
+
+    #[test]
+    fn test_remove_quoted_printable_soft_breaks_lf() {
+        let (cleaned, index_map) = remove_quoted_printable_soft_breaks(b"ab=\ncd=\r\ne".to_vec());
+        assert_eq!(cleaned, b"abcde\0\0\0\0\0");
+        assert_eq!(
+            index_map,
+            [
+                0,
+                1,
+                4,
+                5,
+                9,
+                usize::MAX,
+                usize::MAX,
+                usize::MAX,
+                usize::MAX,
+                usize::MAX
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_quoted_printable_soft_breaks_trailing_equals() {
+        let (cleaned, index_map) = remove_quoted_printable_soft_breaks(b"a=3D=".to_vec());
+        assert_eq!(cleaned, b"a=3D=");
+        assert_eq!(index_map, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_build_reverse_index_map() {
+        let body = b"ab=\r\ncd".to_vec();
+        let (cleaned, index_map) = remove_quoted_printable_soft_breaks(body.clone());
+        let reverse = build_reverse_index_map(&index_map, body.len());
+
+        assert_eq!(
+            reverse,
+            [Some(0), Some(1), None, None, None, Some(2), Some(3)]
+        );
+        for (original, cleaned_index) in reverse.iter().enumerate() {
+            if let Some(cleaned_index) = cleaned_index {
+                assert_eq!(cleaned[*cleaned_index], body[original]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dkim_status_from_detail() {
+        assert_eq!(DkimStatus::from_detail("pass"), DkimStatus::Pass);
+        assert_eq!(
+            DkimStatus::from_detail("fail (body hash did not verify)"),
+            DkimStatus::Fail
+        );
+        assert_eq!(DkimStatus::from_detail("neutral"), DkimStatus::Neutral);
+        assert_eq!(
+            DkimStatus::from_detail("temperror (key unavailable)"),
+            DkimStatus::TempError
+        );
+        assert_eq!(DkimStatus::from_detail("permerror"), DkimStatus::PermError);
+        assert_eq!(DkimStatus::from_detail(""), DkimStatus::PermError);
+
+        let malformed = Email {
+            from_domain: "example.com".to_string(),
+            raw_email: b"not an email".to_vec(),
+            public_key: crate::PublicKey {
+                key: vec![0; 4],
+                key_type: "unknown".to_string(),
+            },
+            external_inputs: Vec::new(),
+        };
+        let outcome = verify_dkim_outcome(&malformed, &Logger::root(slog::Discard, slog::o!()));
+        assert_eq!(outcome.status, DkimStatus::PermError);
+        assert!(!outcome.is_pass());
+        assert!(!outcome.detail.is_empty());
+    }
+
+    #[test]
+    fn test_parse_email_safe() {
+        let parsed = parse_email_safe(
+            b"DKIM-Signature: v=1; d=example.com; s=s1\r\n\
+From: Alice <Alice@Example.com>\r\n\
+Subject: Hi\r\n\
+\r\n\
+Hello\r\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.headers.len(), 3);
+        assert_eq!(parsed.from_address.as_deref(), Some("alice@example.com"));
+        assert_eq!(parsed.body, b"Hello\r\n");
+        assert_eq!(parsed.dkim_signatures[0]["d"], "example.com");
+
+        for input in [
+            &b""[..],
+            b"\xff\xfe\x00",
+            b"From:\r\n\r\n",
+            b"Content-Type: multipart/mixed; boundary=\"\"\r\n\r\n--\r\n",
+            b"Content-Transfer-Encoding: base64\r\n\r\n!!!",
+            b"DKIM-Signature: ;;==;b\r\n\r\n",
+        ] {
+            if let Ok(parsed) = parse_email_safe(input) {
+                assert!(parsed.from_address.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_dkim_batch_isolates_failures() {
+        let malformed = Email {
+            from_domain: "example.com".to_string(),
+            raw_email: b"not an email".to_vec(),
+            public_key: crate::PublicKey {
+                key: vec![0; 4],
+                key_type: "unknown".to_string(),
+            },
+            external_inputs: Vec::new(),
+        };
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        assert_eq!(
+            verify_dkim_batch(&[&malformed, &malformed], &logger),
+            vec![false, false]
+        );
+        assert!(verify_dkim_batch(&[], &logger).is_empty());
+
+        let panicking = Email {
+            raw_email: Vec::new(),
+            ..malformed.clone()
+        };
+        assert_eq!(
+            verify_batch_isolated(&[&panicking, &malformed], |email| {
+                assert!(!email.raw_email.is_empty(), "verifier panicked");
+                Ok("pass".to_string())
+            }),
+            vec![false, true]
+        );
+    }
+
+    #[test]
+    fn test_extract_email_bodies_batch() {
+        let signed = parse_mail(SIGNED_EMAIL).unwrap();
+        let nested = parse_mail(NESTED_EMAIL).unwrap();
+
+        let bodies = extract_email_bodies_batch(&[&signed, &nested]);
+        assert_eq!(bodies[0], extract_email_body(&signed).unwrap());
+        assert_eq!(bodies[1], extract_email_body(&nested).unwrap());
+    }
+
+    #[test]
+    fn test_canonicalize_body_simple() {
+        assert_eq!(canonicalize_body_simple(b""), b"\r\n");
+        assert_eq!(canonicalize_body_simple(b"a \r\n\r\n\r\n"), b"a \r\n");
+        assert_eq!(canonicalize_body_simple(b"a\r\n \r\n"), b"a\r\n \r\n");
+        assert_eq!(canonicalize_body_simple(b"a"), b"a\r\n");
+    }
+
+    #[test]
+    fn test_canonicalize_body_relaxed() {
+        assert_eq!(canonicalize_body_relaxed(b""), b"");
+        assert_eq!(canonicalize_body_relaxed(b"\r\n\r\n"), b"");
+        assert_eq!(
+            canonicalize_body_relaxed(b" a  b \t\r\nc\r\n \r\n\r\n"),
+            b" a b\r\nc\r\n"
+        );
+    }
+
+    #[test]
+    fn test_verify_body_with_length_truncates() {
+        let body = b"\r\nunsigned trailer\r\n";
+        assert!(!verify_body(body, EMPTY_BODY_HASH).unwrap());
+        assert!(verify_body_with_length(body, EMPTY_BODY_HASH, Some(2)).unwrap());
+    }
+
+    #[test]
+    fn test_verify_body_detailed() {
+        let report = verify_body_detailed(b"\r\n", EMPTY_BODY_HASH).unwrap();
+        assert!(report.matches);
+        assert_eq!(report.computed_hash, EMPTY_BODY_HASH);
+        assert_eq!(report.first_difference, None);
+
+        let report =
+            verify_body_detailed_against(b"Hello =\r\nBob\r\n", EMPTY_BODY_HASH, b"Hello Bob\r\n")
+                .unwrap();
+        assert!(!report.matches);
+        assert_eq!(report.expected_hash, EMPTY_BODY_HASH);
+        assert_eq!(report.first_difference, Some(6));
+
+        assert_eq!(first_difference(b"abc", b"abcd"), Some(3));
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+    }
+
+    #[test]
+    fn test_verify_body_auto() {
+        let body = b"Hello\r\n";
+        let fields = |algorithm: &str, body_hash: &[u8]| {
+            HashMap::from([
+                ("a".to_string(), algorithm.to_string()),
+                ("bh".to_string(), STANDARD.encode(body_hash)),
+            ])
+        };
+
+        let sha256 = hash_bytes(body);
+        let sha512 = HashAlgorithm::Sha512.digest(body);
+        assert!(verify_body_auto(body, &fields("rsa-sha256", &sha256)).unwrap());
+        assert!(verify_body_auto(body, &fields("ed25519-sha256", &sha256)).unwrap());
+        assert!(verify_body_auto(body, &fields("rsa-sha512", &sha512)).unwrap());
+        assert!(!verify_body_auto(body, &fields("rsa-sha512", &sha256)).unwrap());
+        assert!(verify_body_auto(body, &fields("rsa-sha1", &sha256)).is_err());
+        assert!(verify_body_auto(body, &fields("x-sha512", &sha512)).is_err());
+        assert!(verify_body_auto(body, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let signed_body = b"Hi  Bob,\r\n\r\nSee you\r\n\r\n";
+        let body_hash = STANDARD.encode(hash_bytes(&canonicalize_body_relaxed(signed_body)));
+        let lf_body = b"Hi  Bob,\n\nSee you\n\n";
+
+        assert!(!verify_body(&canonicalize_body_relaxed(lf_body), &body_hash).unwrap());
+        let normalized = normalize_line_endings(lf_body);
+        assert_eq!(normalized, signed_body);
+        assert!(verify_body(&canonicalize_body_relaxed(&normalized), &body_hash).unwrap());
+        assert_eq!(normalize_line_endings(signed_body), signed_body);
+    }
+
+    #[test]
+    fn test_verify_dkim_detail_normalizes_line_endings() {
+        let mut email = dkim_signed_email(
+            "v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=s1; h=from:to:subject",
+        );
+        let logger = Logger::root(slog::Discard, slog::o!());
+        assert!(verify_dkim_detail(&email, &logger)
+            .unwrap()
+            .starts_with("pass"));
+
+        email.raw_email = String::from_utf8_lossy(&email.raw_email)
+            .replace("\r\n", "\n")
+            .into_bytes();
+        assert!(!verify_dkim_detail(&email, &logger)
+            .unwrap()
+            .starts_with("pass"));
+        let options = DkimOptions {
+            normalize_line_endings: true,
+        };
+        assert!(verify_dkim_detail_with_options(&email, &logger, options)
+            .unwrap()
+            .starts_with("pass"));
+    }
+
+    #[test]
+    fn test_compute_body_hash() {
+        let email =
+            b"DKIM-Signature: v=1; a=rsa-sha256; c=simple/relaxed; d=example.com; s=s1;\r\n\
+\th=from; bh=lGP7WgYuC2BfNW+3tXtZjgpRyTmfyliAJh62YXMvN6E=; b=\r\n\
+From: alice@example.com\r\n\
+\r\n\
+Hello  Bob,\r\n\
+\r\n";
+        let body_hash = compute_body_hash(email).unwrap();
+        assert_eq!(body_hash, "lGP7WgYuC2BfNW+3tXtZjgpRyTmfyliAJh62YXMvN6E=");
+        assert!(verify_body(&canonicalize_body_relaxed(b"Hello  Bob,\r\n"), &body_hash).unwrap());
+    }
+
+    #[test]
+    fn test_verify_body_hash_only() {
+        let header =
+            "DKIM-Signature: v=1; a=rsa-sha256; c=simple/relaxed; d=example.com; s=s1;\r\n\
+\th=from; bh=lGP7WgYuC2BfNW+3tXtZjgpRyTmfyliAJh62YXMvN6E=; b=\r\n\
+From: alice@example.com\r\n\
+\r\n";
+        let email = format!("{header}Hello  Bob,\r\n\r\n");
+        assert!(verify_body_hash_only(email.as_bytes()).unwrap());
+
+        let tampered = format!("{header}Hello  Eve,\r\n\r\n");
+        assert!(!verify_body_hash_only(tampered.as_bytes()).unwrap());
+
+        assert!(verify_body_hash_only(b"From: alice@example.com\r\n\r\nHi\r\n").is_err());
+
+        // Line endings are only normalized on request
+        let lf = email.replace("\r\n", "\n");
+        assert!(!verify_body_hash_only(lf.as_bytes()).unwrap());
+        let options = DkimOptions {
+            normalize_line_endings: true,
+        };
+        assert!(verify_body_hash_only_with_options(lf.as_bytes(), options).unwrap());
+    }
+
+    #[test]
+    fn test_body_hash_ignores_later_signatures() {
+        let email = "DKIM-Signature: v=1; a=rsa-sha256; c=simple/relaxed; d=example.com; s=s1;\r\n\
+\th=from; bh=lGP7WgYuC2BfNW+3tXtZjgpRyTmfyliAJh62YXMvN6E=; b=\r\n\
+DKIM-Signature: v=1; a=rsa-sha256; c=bogus/bogus; d=example.org; s=s2; h=from; bh=; b=!!\r\n\
+From: alice@example.com\r\n\
+\r\n\
+Hello  Bob,\r\n\
+\r\n";
+        assert_eq!(
+            compute_body_hash(email.as_bytes()).unwrap(),
+            "lGP7WgYuC2BfNW+3tXtZjgpRyTmfyliAJh62YXMvN6E="
+        );
+        assert!(verify_body_hash_only(email.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_verify_body_hash_only_sha512() {
+        let body = "Hello  Bob,\r\n\r\n";
+        let body_hash = STANDARD
+            .encode(HashAlgorithm::Sha512.digest(&canonicalize_body_relaxed(body.as_bytes())));
+        let email = format!(
+            "DKIM-Signature: v=1; a=rsa-sha512; c=simple/relaxed; d=example.com; s=s1;\r\n\
+\th=from; bh={body_hash}; b=\r\n\
+From: alice@example.com\r\n\
+\r\n\
+{body}"
+        );
+        assert_eq!(compute_body_hash(email.as_bytes()).unwrap(), body_hash);
+        assert!(verify_body_hash_only(email.as_bytes()).unwrap());
+
+        let sha256 = email.replace("rsa-sha512", "rsa-sha256");
+        assert!(!verify_body_hash_only(sha256.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_verify_body_with_length_clamps() {
+        assert!(verify_body_with_length(b"\r\n", EMPTY_BODY_HASH, Some(1024)).unwrap());
+    }
+}
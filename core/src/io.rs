@@ -1,4 +1,5 @@
 use alloy_sol_types::{sol, SolValue};
+use anyhow::{anyhow, Result};
 
 use crate::EmailVerifierOutput;
 
@@ -15,7 +16,7 @@ sol!(
     }
 );
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum VerificationOutput {
     EmailOnly(EmailVerifierOutput),
     WithRegex {
@@ -32,22 +33,35 @@ impl VerificationOutput {
         }
     }
 
-    pub fn abi_encode(&self) -> Vec<u8> {
-        match self {
-            Self::EmailOnly(email) => SolEmailOutput::abi_encode(&convert_email(email)),
+    /// ABI-encodes the output, failing if either hash is not exactly 32 bytes.
+    pub fn abi_encode(&self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::EmailOnly(email) => SolEmailOutput::abi_encode(&convert_email(email)?),
             Self::WithRegex { email, matches } => (SolEmailWithRegexOutput {
-                email: convert_email(email),
+                email: convert_email(email)?,
                 matches: matches.clone(),
             })
             .abi_encode(),
-        }
+        })
     }
 }
 
-fn convert_email(email: &EmailVerifierOutput) -> SolEmailOutput {
-    SolEmailOutput {
-        from_domain_hash: email.from_domain_hash.as_slice().try_into().unwrap(),
-        public_key_hash: email.public_key_hash.as_slice().try_into().unwrap(),
+fn convert_email(email: &EmailVerifierOutput) -> Result<SolEmailOutput> {
+    Ok(SolEmailOutput {
+        from_domain_hash: email
+            .from_domain_hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| hash_length_error("from_domain_hash", &email.from_domain_hash))?,
+        public_key_hash: email
+            .public_key_hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| hash_length_error("public_key_hash", &email.public_key_hash))?,
         external_inputs: email.external_inputs.clone(),
-    }
+    })
+}
+
+fn hash_length_error(name: &str, hash: &[u8]) -> anyhow::Error {
+    anyhow!("{} must be 32 bytes, got {}", name, hash.len())
 }
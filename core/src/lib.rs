@@ -1,5 +1,6 @@
 mod circuits;
 mod crypto;
+mod dkim;
 mod email;
 mod io;
 mod regex;
@@ -7,6 +8,7 @@ mod structs;
 
 pub use circuits::*;
 pub use crypto::*;
+pub use dkim::*;
 pub use email::*;
 pub use io::*;
 pub use regex::*;
@@ -1,6 +1,18 @@
-use regex_automata::dfa::{dense, regex::Regex};
+#[cfg(feature = "gzip")]
+use std::io::{Read, Write};
+use std::{cell::RefCell, collections::HashMap};
 
-use crate::CompiledRegex;
+#[cfg(feature = "gzip")]
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use regex_automata::{
+    dfa::{dense, regex::Regex, Automaton},
+    Match,
+};
+
+use crate::{header_value_range, CompiledRegex, Endianness, RegexMatch, DFA};
 
 #[cfg(feature = "sp1")]
 fn align_slice(bytes: &[u8]) -> Vec<u8> {
@@ -12,42 +24,640 @@ fn align_slice(bytes: &[u8]) -> Vec<u8> {
     aligned
 }
 
+/// Panics with a descriptive message if `dfa` was serialized for a different byte order than
+/// the host's, which `dense::DFA::from_bytes` would otherwise reject as corrupt.
+fn assert_native_endianness(dfa: &DFA) {
+    assert_eq!(
+        dfa.endianness,
+        Endianness::native(),
+        "DFA was serialized as {:?}-endian, but this target is {:?}-endian",
+        dfa.endianness,
+        Endianness::native()
+    );
+}
+
+/// Copies serialized DFA bytes into a buffer `dense::DFA::from_bytes` can read.
+#[cfg(feature = "sp1")]
+fn dfa_buffer(bytes: &[u8]) -> Vec<u8> {
+    align_slice(bytes)
+}
+
+#[cfg(not(feature = "sp1"))]
+fn dfa_buffer(bytes: &[u8]) -> Vec<u8> {
+    bytes.to_vec()
+}
+
+/// Returns one of a part's serialized DFAs in a buffer `dense::DFA::from_bytes` can read,
+/// decompressing it first if the part is compressed.
+fn part_dfa_buffer(part: &CompiledRegex, bytes: &[u8]) -> Vec<u8> {
+    if part.compressed {
+        dfa_buffer(&decompress_dfa_bytes(bytes))
+    } else {
+        dfa_buffer(bytes)
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn decompress_dfa_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(bytes)
+        .read_to_end(&mut decompressed)
+        .expect("Compressed DFA is not valid gzip");
+    decompressed
+}
+
+#[cfg(not(feature = "gzip"))]
+fn decompress_dfa_bytes(_bytes: &[u8]) -> Vec<u8> {
+    panic!("Loading a compressed DFA requires the gzip feature")
+}
+
+#[cfg(feature = "gzip")]
+impl CompiledRegex {
+    /// Gzip-compresses the `fwd` and `bwd` DFAs to shrink the guest input. Guests loading the
+    /// result must also enable the `gzip` feature. Already compressed parts are returned as-is.
+    pub fn compress(mut self) -> Self {
+        if self.compressed {
+            return self;
+        }
+        let compress = |bytes: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder
+                .write_all(bytes)
+                .and_then(|_| encoder.finish())
+                .expect("Writing to a Vec cannot fail")
+        };
+        self.verify_re.fwd = compress(&self.verify_re.fwd);
+        self.verify_re.bwd = compress(&self.verify_re.bwd);
+        self.compressed = true;
+        self
+    }
+}
+
+/// With the `parallel` feature, [`process_regex_parts_with_options`] matches parts in parallel
+/// when there are more than this many.
+#[cfg(feature = "parallel")]
+const PARALLEL_PARTS_THRESHOLD: usize = 2;
+
+/// Maximum number of deserialized regexes kept by [`process_regex_parts_cached`] per thread.
+const REGEX_CACHE_CAPACITY: usize = 64;
+
+thread_local! {
+    /// Deserialized regexes keyed by their serialized forward and backward DFA bytes.
+    static REGEX_CACHE: RefCell<HashMap<Vec<u8>, Regex<dense::OwnedDFA>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Compiles `pattern` into a serialized [`DFA`] in memory, the same way the helpers crate does
+/// when generating inputs, so tests can build `CompiledRegex` values without DFA fixture files.
+///
+/// # Panics
+///
+/// Panics if `pattern` is not a valid regex.
+#[cfg(any(test, feature = "test-utils"))]
+pub fn compile_test_dfa(pattern: &str) -> DFA {
+    let re = Regex::new(pattern).expect("Invalid test regex pattern");
+    serialize_dfa(&re, Endianness::Little)
+}
+
+/// Serializes a regex's forward and reverse DFAs into the canonical stored form of a [`DFA`]:
+/// the bytes returned by `to_bytes_*_endian` with their leading alignment padding stripped.
+///
+/// [`process_regex_parts`] re-aligns the bytes where needed, so every producer of [`DFA`]
+/// values should go through this function to keep them byte-identical.
+pub fn serialize_dfa<A: AsRef<[u32]>>(re: &Regex<dense::DFA<A>>, endianness: Endianness) -> DFA {
+    let serialize = |dfa: &dense::DFA<A>| {
+        let (bytes, padding) = match endianness {
+            Endianness::Little => dfa.to_bytes_little_endian(),
+            Endianness::Big => dfa.to_bytes_big_endian(),
+        };
+        bytes[padding..].to_vec()
+    };
+    DFA {
+        fwd: serialize(re.forward()),
+        bwd: serialize(re.reverse()),
+        endianness,
+    }
+}
+
+/// How many times a pattern must match the input for a regex part to be verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchPolicy {
+    /// The pattern must match exactly once.
+    #[default]
+    ExactlyOnce,
+    /// The pattern must match at least once; captures are checked against the first match.
+    AtLeastOnce,
+    /// The pattern must match exactly `n` times, with `n >= 1`; captures are checked against the
+    /// first match. Use `must_not_match` to assert that a pattern is absent.
+    Exactly(usize),
+}
+
+/// Returns the first match of `re` in `input` if the number of matches satisfies `policy`.
+///
+/// The search stops as soon as the policy is decided, so memory stays constant no matter how
+/// many times the pattern occurs in the input.
+fn find_policy_match<A: Automaton>(
+    re: &Regex<A>,
+    input: &[u8],
+    policy: MatchPolicy,
+) -> Option<Match> {
+    let mut matches = re.find_iter(input);
+    let first = matches.next()?;
+    let satisfied = match policy {
+        MatchPolicy::ExactlyOnce => matches.next().is_none(),
+        MatchPolicy::AtLeastOnce => true,
+        MatchPolicy::Exactly(n) => 1 + matches.take(n).count() == n,
+    };
+    satisfied.then_some(first)
+}
+
+/// Returns the offset of `capture` in the matched bytes if it occurs exactly once, so each
+/// expected capture is bound to a single position of the match. Empty captures (from optional
+/// groups) are placed at the start of the match.
+fn find_capture_once(matched: &[u8], capture: &[u8]) -> Option<usize> {
+    if capture.is_empty() {
+        return Some(0);
+    }
+
+    let mut offsets = matched
+        .windows(capture.len())
+        .enumerate()
+        .filter(|(_, window)| *window == capture)
+        .map(|(offset, _)| offset);
+    match (offsets.next(), offsets.next()) {
+        (Some(offset), None) => Some(offset),
+        _ => None,
+    }
+}
+
 pub fn process_regex_parts(
     compiled_regexes: &[CompiledRegex],
     input: &[u8],
 ) -> (bool, Vec<String>) {
+    process_regex_parts_with_policy(compiled_regexes, input, MatchPolicy::ExactlyOnce)
+}
+
+/// Same as [`process_regex_parts`], with the number of matches required per part set by `policy`.
+pub fn process_regex_parts_with_policy(
+    compiled_regexes: &[CompiledRegex],
+    input: &[u8],
+    policy: MatchPolicy,
+) -> (bool, Vec<String>) {
+    let (verified, regex_matches) = match_regex_parts(compiled_regexes, input, policy);
+    (
+        verified,
+        regex_matches.into_iter().map(|m| m.capture).collect(),
+    )
+}
+
+/// Same as [`process_regex_parts`], also returning the byte range of each capture in `input`.
+pub fn process_regex_parts_with_spans(
+    compiled_regexes: &[CompiledRegex],
+    input: &[u8],
+) -> (bool, Vec<RegexMatch>) {
+    match_regex_parts(compiled_regexes, input, MatchPolicy::ExactlyOnce)
+}
+
+/// Options of [`process_regex_parts_with_options`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProcessOptions {
+    /// Matches the parts one after another even with the `parallel` feature, so a guest follows
+    /// the same code path whatever the number of parts.
+    pub force_sequential: bool,
+}
+
+/// Same as [`process_regex_parts`], matching the parts in parallel when there are more than
+/// two of them, the `parallel` feature is enabled and `options.force_sequential` is not set.
+///
+/// The output is identical in both modes: captures are returned in part order, up to and
+/// including the first part that fails to verify.
+pub fn process_regex_parts_with_options(
+    compiled_regexes: &[CompiledRegex],
+    input: &[u8],
+    options: ProcessOptions,
+) -> (bool, Vec<String>) {
+    #[cfg(feature = "parallel")]
+    if !options.force_sequential && compiled_regexes.len() > PARALLEL_PARTS_THRESHOLD {
+        return process_regex_parts_parallel(compiled_regexes, input);
+    }
+    #[cfg(not(feature = "parallel"))]
+    let _ = options;
+
+    process_regex_parts(compiled_regexes, input)
+}
+
+/// Matches every part on the rayon thread pool, then merges the results in part order the way
+/// [`match_regex_parts`] would have produced them.
+#[cfg(feature = "parallel")]
+fn process_regex_parts_parallel(
+    compiled_regexes: &[CompiledRegex],
+    input: &[u8],
+) -> (bool, Vec<String>) {
+    let results: Vec<(bool, Vec<RegexMatch>)> = compiled_regexes
+        .par_iter()
+        .map(|part| {
+            let mut part_matches = Vec::new();
+            let verified = match_part(
+                &load_owned_regex(part),
+                part,
+                input,
+                MatchPolicy::ExactlyOnce,
+                &mut part_matches,
+            );
+            (verified, part_matches)
+        })
+        .collect();
+
+    let mut captures = Vec::new();
+    for (verified, part_matches) in results {
+        captures.extend(part_matches.into_iter().map(|m| m.capture));
+        if !verified {
+            return (false, captures);
+        }
+    }
+    (true, captures)
+}
+
+fn match_regex_parts(
+    compiled_regexes: &[CompiledRegex],
+    input: &[u8],
+    policy: MatchPolicy,
+) -> (bool, Vec<RegexMatch>) {
     let mut regex_matches = Vec::new();
 
     for part in compiled_regexes {
-        #[cfg(feature = "sp1")]
-        let fwd = align_slice(&part.verify_re.fwd);
-        #[cfg(not(feature = "sp1"))]
-        let fwd = part.verify_re.fwd.clone();
-
-        #[cfg(feature = "sp1")]
-        let bwd = align_slice(&part.verify_re.bwd);
-        #[cfg(not(feature = "sp1"))]
-        let bwd = part.verify_re.bwd.clone();
+        assert_native_endianness(&part.verify_re);
+        let fwd = part_dfa_buffer(part, &part.verify_re.fwd);
+        let bwd = part_dfa_buffer(part, &part.verify_re.bwd);
 
         let fwd = dense::DFA::from_bytes(&fwd).unwrap().0;
         let bwd = dense::DFA::from_bytes(&bwd).unwrap().0;
         let re = Regex::builder().build_from_dfas(fwd, bwd);
 
-        let matches: Vec<_> = re.find_iter(input).collect();
-        if matches.len() != 1 {
+        if !match_part(&re, part, input, policy, &mut regex_matches) {
             return (false, regex_matches);
         }
+    }
 
-        if let Some(captures) = part.captures.as_ref() {
-            for capture in captures.iter() {
-                let matched_str = String::from_utf8_lossy(&input[matches[0].range()]);
-                if !matched_str.contains(capture) {
-                    return (false, regex_matches);
-                }
-                regex_matches.push(capture.to_string());
+    (true, regex_matches)
+}
+
+/// Matches one part against `input`, appending its captures to `regex_matches`. Returns `false`
+/// if the part is not verified.
+fn match_part<A: Automaton>(
+    re: &Regex<A>,
+    part: &CompiledRegex,
+    input: &[u8],
+    policy: MatchPolicy,
+    regex_matches: &mut Vec<RegexMatch>,
+) -> bool {
+    // A part bound to a header only searches that header's value; a missing header leaves
+    // nothing to search
+    let (offset, input) = match &part.header_name {
+        Some(name) => header_value_range(input, name)
+            .map_or((0, &[][..]), |range| (range.start, &input[range])),
+        None => (0, input),
+    };
+
+    if part.must_not_match {
+        return !re.is_match(input);
+    }
+
+    let Some(matched) = find_policy_match(re, input, policy) else {
+        return false;
+    };
+
+    if let Some(captures) = part.captures.as_ref() {
+        let matched_bytes = &input[matched.range()];
+        for capture in captures.iter() {
+            let Some(capture_offset) = find_capture_once(matched_bytes, capture.as_bytes()) else {
+                return false;
+            };
+            let start = offset + matched.start() + capture_offset;
+            regex_matches.push(RegexMatch {
+                capture: capture.to_string(),
+                start,
+                end: start + capture.len(),
+            });
+        }
+    }
+
+    true
+}
+
+/// Same as [`process_regex_parts`], reusing DFAs deserialized by earlier calls on the same
+/// thread.
+///
+/// The cache is thread-local and keyed by the full serialized DFA bytes of each part, never by
+/// the input, so every input is matched afresh and distinct patterns cannot collide. When it
+/// holds [`REGEX_CACHE_CAPACITY`] regexes and a new one is needed, it is cleared entirely. This
+/// is a host-side optimization; guests should use [`process_regex_parts`].
+pub fn process_regex_parts_cached(
+    compiled_regexes: &[CompiledRegex],
+    input: &[u8],
+) -> (bool, Vec<String>) {
+    REGEX_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let mut regex_matches = Vec::new();
+
+        for part in compiled_regexes {
+            let key = regex_cache_key(part);
+            if !cache.contains_key(&key) && cache.len() >= REGEX_CACHE_CAPACITY {
+                cache.clear();
+            }
+            let re = cache.entry(key).or_insert_with(|| load_owned_regex(part));
+
+            if !match_part(
+                re,
+                part,
+                input,
+                MatchPolicy::ExactlyOnce,
+                &mut regex_matches,
+            ) {
+                return (
+                    false,
+                    regex_matches.into_iter().map(|m| m.capture).collect(),
+                );
             }
         }
+
+        (true, regex_matches.into_iter().map(|m| m.capture).collect())
+    })
+}
+
+/// Concatenates the DFA bytes of a part, prefixed with the forward DFA's length so that
+/// different splits of the same bytes produce different keys, and with whether they are
+/// compressed.
+fn regex_cache_key(part: &CompiledRegex) -> Vec<u8> {
+    let fwd_len = (part.verify_re.fwd.len() as u64).to_le_bytes();
+    [
+        &[part.compressed as u8][..],
+        &fwd_len[..],
+        &part.verify_re.fwd,
+        &part.verify_re.bwd,
+    ]
+    .concat()
+}
+
+/// Deserializes the DFAs of a part into an owned regex.
+///
+/// # Panics
+///
+/// Panics if the DFA bytes are invalid, as [`process_regex_parts`] does.
+fn load_owned_regex(part: &CompiledRegex) -> Regex<dense::OwnedDFA> {
+    assert_native_endianness(&part.verify_re);
+    let load = |bytes: &[u8]| {
+        dense::DFA::from_bytes(&part_dfa_buffer(part, bytes))
+            .unwrap()
+            .0
+            .to_owned()
+    };
+    Regex::builder().build_from_dfas(load(&part.verify_re.fwd), load(&part.verify_re.bwd))
+}
+
+/// Matches a fixed set of regex parts against many inputs, deserializing their DFAs once
+/// instead of on every call.
+///
+/// This is a host-side optimization for batch processing; guests verify a single input and
+/// keep using the stateless [`process_regex_parts`].
+pub struct RegexMatcher<'a> {
+    parts: &'a [CompiledRegex],
+    regexes: Vec<Regex<dense::OwnedDFA>>,
+}
+
+impl<'a> RegexMatcher<'a> {
+    /// # Panics
+    ///
+    /// Panics if the DFA bytes of a part cannot be deserialized, as [`process_regex_parts`]
+    /// does.
+    pub fn new(parts: &'a [CompiledRegex]) -> Self {
+        let regexes = parts.iter().map(load_owned_regex).collect();
+
+        Self { parts, regexes }
+    }
+
+    /// Same as [`process_regex_parts`] with the parts given to [`RegexMatcher::new`].
+    pub fn matches(&self, input: &[u8]) -> (bool, Vec<String>) {
+        let mut regex_matches = Vec::new();
+        let verified = self.regexes.iter().zip(self.parts).all(|(re, part)| {
+            match_part(
+                re,
+                part,
+                input,
+                MatchPolicy::ExactlyOnce,
+                &mut regex_matches,
+            )
+        });
+
+        (
+            verified,
+            regex_matches.into_iter().map(|m| m.capture).collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(pattern: &str, captures: &[&str]) -> CompiledRegex {
+        CompiledRegex {
+            verify_re: compile_test_dfa(pattern),
+            captures: Some(captures.iter().map(|c| c.to_string()).collect()),
+            must_not_match: false,
+            header_name: None,
+            compressed: false,
+        }
     }
 
-    (true, regex_matches)
+    #[test]
+    fn test_process_regex_parts_with_inline_dfa() {
+        let parts = vec![part(r"Amount: \$[0-9]+", &["$42"])];
+
+        assert_eq!(
+            process_regex_parts(&parts, b"Hello\r\nAmount: $42\r\n"),
+            (true, vec!["$42".to_string()])
+        );
+        assert!(!process_regex_parts(&parts, b"Amount: $1, Amount: $2").0);
+    }
+
+    #[test]
+    fn test_process_regex_parts_multiple_captures() {
+        let parts = vec![part(
+            r"Date: [0-9]{4}-[0-9]{2}, Amount: \$[0-9]+, Ref: [A-Z]+",
+            &["2024-05", "$42", "ABC"],
+        )];
+
+        assert_eq!(
+            process_regex_parts(&parts, b"Date: 2024-05, Amount: $42, Ref: ABC").1,
+            vec!["2024-05", "$42", "ABC"]
+        );
+        assert!(!process_regex_parts(&parts, b"Date: 2024-05, Amount: $42, Ref: ABD").0);
+    }
+
+    #[test]
+    fn test_process_regex_parts_with_policy() {
+        let parts = vec![part(r"Amount: \$[0-9]+", &["$1"])];
+        let input = b"Amount: $1, Amount: $2";
+
+        assert!(!process_regex_parts(&parts, input).0);
+        assert_eq!(
+            process_regex_parts_with_policy(&parts, input, MatchPolicy::AtLeastOnce),
+            (true, vec!["$1".to_string()])
+        );
+        assert!(process_regex_parts_with_policy(&parts, input, MatchPolicy::Exactly(2)).0);
+        assert!(!process_regex_parts_with_policy(&parts, input, MatchPolicy::Exactly(3)).0);
+    }
+
+    #[test]
+    fn test_process_regex_parts_with_spans() {
+        let parts = vec![part(r"Total: \$[0-9,]+\.[0-9]{2}", &["$1,234.56"])];
+        let input = b"Hi\r\nTotal: $1,234.56\r\n";
+
+        let (verified, matches) = process_regex_parts_with_spans(&parts, input);
+        assert!(verified);
+        assert_eq!(
+            matches,
+            vec![RegexMatch {
+                capture: "$1,234.56".to_string(),
+                start: 11,
+                end: 20,
+            }]
+        );
+        assert_eq!(&input[11..20], b"$1,234.56");
+    }
+
+    #[test]
+    fn test_process_regex_parts_with_header_name() {
+        let header = b"from:alice@example.com\r\nsubject:Order 42 shipped\r\nto:Order 7\r\n";
+        let order = |header_name: Option<&str>| CompiledRegex {
+            header_name: header_name.map(str::to_string),
+            ..part("Order [0-9]+", &["42"])
+        };
+
+        assert!(!process_regex_parts(&[order(None)], header).0);
+        let (verified, matches) = process_regex_parts_with_spans(&[order(Some("Subject"))], header);
+        assert!(verified);
+        assert_eq!(&header[matches[0].start..matches[0].end], b"42");
+        assert!(!process_regex_parts(&[order(Some("Cc"))], header).0);
+    }
+
+    #[test]
+    fn test_regex_matcher_matches_stateless_path() {
+        let parts = vec![
+            part(r"Amount: \$[0-9]+", &["$42"]),
+            CompiledRegex {
+                captures: None,
+                must_not_match: true,
+                ..part("CANCELLED", &[])
+            },
+        ];
+        let matcher = RegexMatcher::new(&parts);
+
+        for input in [
+            &b"Amount: $42"[..],
+            b"Amount: $42 CANCELLED",
+            b"Amount: $7",
+            b"",
+        ] {
+            assert_eq!(matcher.matches(input), process_regex_parts(&parts, input));
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_compressed_dfa_matches_uncompressed() {
+        let amount = || part(r"Amount: \$[0-9]+", &["$42"]);
+        let uncompressed = vec![amount()];
+        let compressed = vec![amount().compress()];
+        assert!(compressed[0].compressed);
+        assert!(compressed[0].verify_re.fwd.len() < uncompressed[0].verify_re.fwd.len());
+
+        for input in [&b"Hello\r\nAmount: $42\r\n"[..], b"Amount: $7", b""] {
+            assert_eq!(
+                process_regex_parts(&compressed, input),
+                process_regex_parts(&uncompressed, input)
+            );
+            assert_eq!(
+                process_regex_parts_cached(&compressed, input),
+                process_regex_parts(&uncompressed, input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_process_regex_parts_cached_matches_uncached() {
+        let amount = vec![part(r"Amount: \$[0-9]+", &["$42"])];
+        let reference = vec![part("Ref: [A-Z]+", &["ABC"])];
+
+        for input in [&b"Amount: $42"[..], b"Amount: $7", b"Amount: $42, Ref: ABC"] {
+            assert_eq!(
+                process_regex_parts_cached(&amount, input),
+                process_regex_parts(&amount, input)
+            );
+            assert_eq!(
+                process_regex_parts_cached(&reference, input),
+                process_regex_parts(&reference, input)
+            );
+        }
+        REGEX_CACHE.with(|cache| assert_eq!(cache.borrow().len(), 2));
+    }
+
+    #[test]
+    fn test_process_regex_parts_with_options_is_mode_independent() {
+        let parts = vec![
+            part("Amount: [0-9]+", &["42"]),
+            part("Ref: [A-Z]+", &["ABC"]),
+            part("Date: [0-9-]+", &["2024-01-02"]),
+        ];
+
+        for input in [
+            &b"Amount: 42, Ref: ABC, Date: 2024-01-02"[..],
+            b"Amount: 42, Ref: XYZ, Date: 2024-01-02",
+            b"Amount: 42",
+        ] {
+            let sequential = process_regex_parts_with_options(
+                &parts,
+                input,
+                ProcessOptions {
+                    force_sequential: true,
+                },
+            );
+            let default =
+                process_regex_parts_with_options(&parts, input, ProcessOptions::default());
+            assert_eq!(sequential, default);
+            assert_eq!(sequential, process_regex_parts(&parts, input));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "DFA was serialized as")]
+    fn test_process_regex_parts_rejects_foreign_endianness() {
+        let mut digits = CompiledRegex {
+            captures: None,
+            ..part("[0-9]+", &[])
+        };
+        digits.verify_re.endianness = match Endianness::native() {
+            Endianness::Little => Endianness::Big,
+            Endianness::Big => Endianness::Little,
+        };
+        let parts = vec![digits];
+
+        process_regex_parts(&parts, b"42");
+    }
+
+    #[test]
+    fn test_process_regex_parts_must_not_match() {
+        let parts = vec![CompiledRegex {
+            captures: None,
+            must_not_match: true,
+            ..part("CANCELLED", &[])
+        }];
+
+        assert_eq!(
+            process_regex_parts(&parts, b"Your order has shipped"),
+            (true, vec![])
+        );
+        assert!(!process_regex_parts(&parts, b"Your order was CANCELLED").0);
+    }
 }
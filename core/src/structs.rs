@@ -2,74 +2,381 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "json")]
+use crate::to_hex;
+
 #[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
-#[cfg_attr(feature = "sp1", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicKey {
     pub key: Vec<u8>,
     pub key_type: String,
 }
 
+/// Byte order of serialized DFAs.
+///
+/// `dense::DFA::from_bytes` only reads DFAs in the byte order of the host, so DFAs must be
+/// serialized for the target that loads them. Little-endian is the default and matches the
+/// zkVM targets.
 #[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
-#[cfg_attr(feature = "sp1", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// Returns the byte order of the target this code was compiled for.
+    pub fn native() -> Self {
+        if cfg!(target_endian = "big") {
+            Self::Big
+        } else {
+            Self::Little
+        }
+    }
+}
+
+#[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DFA {
+    /// The serialized forward DFA, without alignment padding; see `serialize_dfa`.
     pub fwd: Vec<u8>,
+    /// The serialized reverse DFA, without alignment padding.
     pub bwd: Vec<u8>,
+    /// The byte order `fwd` and `bwd` were serialized in.
+    #[serde(default)]
+    pub endianness: Endianness,
 }
 
 #[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
-#[cfg_attr(feature = "sp1", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CompiledRegex {
     pub verify_re: DFA,
     pub captures: Option<Vec<String>>,
+    /// Inverts the assertion: the pattern must not match the input at all.
+    #[serde(default)]
+    pub must_not_match: bool,
+    /// Restricts a header part to the value of the named header instead of the whole
+    /// canonicalized header block.
+    pub header_name: Option<String>,
+    /// Whether the `verify_re` DFAs are gzip-compressed; see `CompiledRegex::compress`. Loading
+    /// compressed DFAs requires the `gzip` feature.
+    #[serde(default)]
+    pub compressed: bool,
+}
+
+/// Which form of the email's header block header regex parts are matched against.
+#[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HeaderInput {
+    /// The signed headers, canonicalized as declared by the DKIM signature's `c=` tag.
+    #[default]
+    Canonicalized,
+    /// The fields selected by the DKIM signature's `h=` tag as they appear in the raw email, in
+    /// signing order, for case-sensitive patterns that relaxed canonicalization would break.
+    ///
+    /// Unsigned fields are left out, but the exact bytes are not covered by the signature: with
+    /// `relaxed` header canonicalization, the case and whitespace of the selected fields can be
+    /// changed in transit without breaking it.
+    Raw,
+}
+
+/// Which form of the email's body body regex parts are matched against.
+#[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchTarget {
+    /// The body as signed: canonicalized as declared by the `c=` tag, with quoted-printable soft
+    /// line breaks removed. A base64 body stays encoded, so patterns must target the encoding.
+    #[default]
+    SignedWire,
+    /// The body as displayed: the part selected by [`crate::extract_email_body`], decoded from
+    /// its `Content-Transfer-Encoding`. Requires a signature without `l=` whose `h=` covers
+    /// `Content-Type` and `Content-Transfer-Encoding`, see [`crate::body_match_input`].
+    DecodedText,
 }
 
 #[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
-#[cfg_attr(feature = "sp1", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RegexInfo {
     pub header_parts: Option<Vec<CompiledRegex>>,
     pub body_parts: Option<Vec<CompiledRegex>>,
+    #[serde(default)]
+    pub header_input: HeaderInput,
+    #[serde(default)]
+    pub match_target: MatchTarget,
 }
 
 #[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
-#[cfg_attr(feature = "sp1", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExternalInput {
     pub name: String,
     pub value: Option<String>,
     pub max_length: usize,
+    /// When false, a missing `value` is committed as an empty string instead of failing
+    /// verification. Defaults to true when absent from serialized inputs.
+    #[serde(default = "default_required")]
+    pub required: bool,
+}
+
+fn default_required() -> bool {
+    true
 }
 
 #[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
-#[cfg_attr(feature = "sp1", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Email {
     pub from_domain: String,
     pub raw_email: Vec<u8>,
     pub public_key: PublicKey,
+    /// Committed in this order as `[name1, value1, name2, value2, ...]`.
     pub external_inputs: Vec<ExternalInput>,
 }
 
 #[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
-#[cfg_attr(feature = "sp1", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct EmailWithRegex {
     pub email: Email,
     pub regex_info: RegexInfo,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EmailVerifierOutput {
     pub from_domain_hash: Vec<u8>,
     pub public_key_hash: Vec<u8>,
     pub external_inputs: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A verified regex capture and its byte range in the searched input.
+#[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegexMatch {
+    pub capture: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[cfg_attr(feature = "risc0", derive(BorshSerialize, BorshDeserialize))]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EmailWithRegexVerifierOutput {
     pub email: EmailVerifierOutput,
     pub regex_matches: Vec<String>,
 }
+
+/// JSON form of [`EmailVerifierOutput`] with `0x`-prefixed hex hashes.
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct EmailVerifierOutputJson<'a> {
+    from_domain_hash: String,
+    public_key_hash: String,
+    external_inputs: &'a [String],
+}
+
+#[cfg(feature = "json")]
+impl<'a> From<&'a EmailVerifierOutput> for EmailVerifierOutputJson<'a> {
+    fn from(output: &'a EmailVerifierOutput) -> Self {
+        Self {
+            from_domain_hash: format!("0x{}", to_hex(&output.from_domain_hash)),
+            public_key_hash: format!("0x{}", to_hex(&output.public_key_hash)),
+            external_inputs: &output.external_inputs,
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl EmailVerifierOutput {
+    /// Serializes the output as JSON for web clients, with the hashes as `0x`-prefixed hex
+    /// strings instead of byte arrays. Fields keep their declaration order.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&EmailVerifierOutputJson::from(self))
+            .expect("Serializing strings to JSON cannot fail")
+    }
+}
+
+#[cfg(feature = "json")]
+impl EmailWithRegexVerifierOutput {
+    /// Same as [`EmailVerifierOutput::to_json`], with the regex matches alongside.
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Json<'a> {
+            email: EmailVerifierOutputJson<'a>,
+            regex_matches: &'a [String],
+        }
+
+        serde_json::to_string(&Json {
+            email: EmailVerifierOutputJson::from(&self.email),
+            regex_matches: &self.regex_matches,
+        })
+        .expect("Serializing strings to JSON cannot fail")
+    }
+}
+
+/// A JSON-friendly summary of a DKIM verification, as returned by `verify_report`.
+#[derive(Debug, Serialize)]
+pub struct VerificationReport {
+    /// The `d=` signing domain.
+    pub domain: String,
+    /// The `s=` selector.
+    pub selector: String,
+    /// The `a=` signing algorithm.
+    pub algorithm: String,
+    /// Whether the header signature verifies, ignoring the body hash.
+    pub signature_valid: bool,
+    /// Whether the canonicalized body matches the `bh=` body hash.
+    pub body_hash_valid: bool,
+    /// The detail string of the full DKIM verification, e.g. `pass`.
+    pub dkim_detail: String,
+    /// Hex-encoded hash of the `From` domain, as committed by `verify_email`.
+    pub from_domain_hash: String,
+    /// Hex-encoded hash of the public key, as committed by `verify_email`.
+    pub public_key_hash: String,
+}
+
+/// The outcome of `verify_email_offline` for the signature it checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationResult {
+    /// Index of the checked DKIM-Signature header, in header order.
+    pub signature_index: usize,
+    /// The `d=` signing domain.
+    pub domain: String,
+    /// The `s=` selector.
+    pub selector: String,
+    /// Whether the canonicalized body matches the `bh=` body hash.
+    pub body_hash_valid: bool,
+    /// Whether the header signature verifies against the given key.
+    pub signature_valid: bool,
+}
+
+impl VerificationResult {
+    /// Returns `true` if both the body hash and the signature verified.
+    pub fn is_valid(&self) -> bool {
+        self.body_hash_valid && self.signature_valid
+    }
+}
+
+/// A body hash comparison, as returned by `verify_body_detailed`, for debugging why a body does
+/// not match its `bh=` tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BodyHashReport {
+    /// The base64 SHA-256 of the body that was passed in.
+    pub computed_hash: String,
+    /// The expected base64 `bh=` value, with folding whitespace removed.
+    pub expected_hash: String,
+    /// Whether the computed hash matches the expected one.
+    pub matches: bool,
+    /// The first offset at which the body differs from a known-good reference body, when one
+    /// was given to `verify_body_detailed_against` and the two differ.
+    pub first_difference: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email_with_regex() -> EmailWithRegex {
+        EmailWithRegex {
+            email: Email {
+                from_domain: "example.com".to_string(),
+                raw_email: b"From: alice@example.com\r\n\r\nHello\r\n".to_vec(),
+                public_key: PublicKey {
+                    key: vec![1, 2, 3],
+                    key_type: "rsa".to_string(),
+                },
+                external_inputs: vec![ExternalInput {
+                    name: "handle".to_string(),
+                    value: Some("alice".to_string()),
+                    max_length: 16,
+                    required: true,
+                }],
+            },
+            regex_info: RegexInfo {
+                header_parts: None,
+                body_parts: Some(vec![CompiledRegex {
+                    verify_re: DFA {
+                        fwd: vec![4, 5],
+                        bwd: vec![6],
+                        endianness: Endianness::Little,
+                    },
+                    captures: Some(vec!["Hello".to_string()]),
+                    must_not_match: false,
+                    header_name: None,
+                    compressed: false,
+                }]),
+                header_input: HeaderInput::Raw,
+                match_target: MatchTarget::DecodedText,
+            },
+        }
+    }
+
+    fn output() -> EmailWithRegexVerifierOutput {
+        EmailWithRegexVerifierOutput {
+            email: EmailVerifierOutput {
+                from_domain_hash: vec![7; 32],
+                public_key_hash: vec![8; 32],
+                external_inputs: vec!["handle".to_string(), "alice".to_string()],
+            },
+            regex_matches: vec!["Hello".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let json = serde_json::to_string(&email_with_regex()).unwrap();
+        let decoded: EmailWithRegex = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+
+        let json = serde_json::to_string(&output()).unwrap();
+        let decoded: EmailWithRegexVerifierOutput = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, output());
+    }
+
+    #[test]
+    fn test_external_input_required_defaults_to_true() {
+        let input: ExternalInput =
+            serde_json::from_str(r#"{"name": "handle", "value": null, "max_length": 16}"#).unwrap();
+        assert!(input.required);
+        assert_eq!(input.value, None);
+    }
+
+    #[test]
+    fn test_compiled_regex_flags_default_to_false() {
+        let part: CompiledRegex =
+            serde_json::from_str(r#"{"verify_re": {"fwd": [4, 5], "bwd": [6]}, "captures": null}"#)
+                .unwrap();
+        assert!(!part.must_not_match && !part.compressed);
+        assert_eq!(part.header_name, None);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_to_json_hex_encodes_hashes() {
+        let hash = |byte: &str| format!("0x{}", byte.repeat(32));
+        assert_eq!(
+            output().email.to_json(),
+            format!(
+                r#"{{"from_domain_hash":"{}","public_key_hash":"{}","external_inputs":["handle","alice"]}}"#,
+                hash("07"),
+                hash("08")
+            )
+        );
+        assert_eq!(
+            output().to_json(),
+            format!(
+                r#"{{"email":{},"regex_matches":["Hello"]}}"#,
+                output().email.to_json()
+            )
+        );
+    }
+
+    #[cfg(feature = "risc0")]
+    #[test]
+    fn test_borsh_round_trip() {
+        let bytes = borsh::to_vec(&email_with_regex()).unwrap();
+        let decoded: EmailWithRegex = borsh::from_slice(&bytes).unwrap();
+        assert_eq!(borsh::to_vec(&decoded).unwrap(), bytes);
+
+        let bytes = borsh::to_vec(&output()).unwrap();
+        let decoded: EmailWithRegexVerifierOutput = borsh::from_slice(&bytes).unwrap();
+        assert_eq!(decoded, output());
+    }
+}
@@ -0,0 +1,69 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use zkemail_core::{Email, ExternalInput};
+
+use crate::{
+    generator::{find_verified_signature, SigningDomain},
+    DkimResolver,
+};
+
+/// Builds a verified [`Email`] from a raw email, fetching its DKIM key.
+///
+/// ```ignore
+/// let email = EmailBuilder::from_raw(raw_email).build().await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct EmailBuilder {
+    raw_email: Vec<u8>,
+    domain: Option<String>,
+    external_inputs: Vec<ExternalInput>,
+    nameservers: Vec<IpAddr>,
+}
+
+impl EmailBuilder {
+    pub fn from_raw(raw_email: impl Into<Vec<u8>>) -> Self {
+        Self {
+            raw_email: raw_email.into(),
+            domain: None,
+            external_inputs: Vec::new(),
+            nameservers: Vec::new(),
+        }
+    }
+
+    /// Sets the signing domain to verify against instead of detecting it from the signatures.
+    pub fn with_domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn with_external_inputs(mut self, external_inputs: Vec<ExternalInput>) -> Self {
+        self.external_inputs = external_inputs;
+        self
+    }
+
+    /// Sets the nameservers used to fetch the DKIM key over plain DNS instead of 8.8.8.8.
+    pub fn with_nameservers(mut self, nameservers: Vec<IpAddr>) -> Self {
+        self.nameservers = nameservers;
+        self
+    }
+
+    /// Fetches the DKIM key and verifies the email, as [`crate::generate_email_inputs`] does.
+    ///
+    /// Without an explicit domain, the signing domain is detected as in
+    /// [`crate::generate_email_inputs_auto`].
+    pub async fn build(self) -> Result<Email> {
+        let signing_domain = match &self.domain {
+            Some(domain) => SigningDomain::Exact(domain),
+            None => SigningDomain::Any,
+        };
+        let resolver = DkimResolver::new().with_nameservers(self.nameservers);
+        find_verified_signature(
+            &self.raw_email,
+            signing_domain,
+            Some(self.external_inputs),
+            &resolver,
+        )
+        .await
+    }
+}
@@ -1,118 +1,886 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
 use anyhow::{anyhow, Result};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use cfdkim::{dns::from_tokio_resolver, public_key::retrieve_public_key, DkimPublicKey};
 use chrono::{DateTime, Utc};
-use reqwest::Client;
+use futures::stream::{self, StreamExt};
+use reqwest::{header::ACCEPT, Client};
 use rsa::{
     pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey},
-    pkcs8::DecodePublicKey,
     RsaPublicKey,
 };
 use serde::Deserialize;
-use slog::Logger;
+use slog::{o, Discard, Logger};
 use trust_dns_resolver::{
     config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
     TokioAsyncResolver,
 };
+use zkemail_core::{parse_dkim_tags, parse_rsa_public_key, rsa_key_bits};
+
+use crate::generator::{find_verified_signature, SigningDomain};
 
 const ARCHIVE_API: &str = "https://archive.prove.email/api";
+const CLOUDFLARE_DOH_API: &str = "https://cloudflare-dns.com/dns-query";
+const TXT_RECORD_TYPE: u16 = 16;
+/// Google Public DNS, used by [`DnsBackend::Google`] when no nameservers are configured.
+const DEFAULT_NAMESERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+const NXDOMAIN_STATUS: u32 = 3;
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Timeout of a single request to the ZK Email archive.
+const ARCHIVE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_ARCHIVE_RESPONSE_BYTES: usize = 1024 * 1024;
+/// Upper bound on the backoff exponent, so long retry chains cannot overflow the delay.
+const MAX_BACKOFF_SHIFT: u32 = 16;
 
-#[derive(Debug, Deserialize)]
-struct DkimKeyResponse {
-    value: String,
-    selector: String,
+/// HTTP client shared by every [`DkimResolver`], so they reuse one connection pool.
+static SHARED_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// A DKIM key as returned by [`fetch_dkim_key`]: the key bytes and the key type.
+type DkimKey = (Vec<u8>, String);
+
+/// A DKIM key record of a domain known to the ZK Email archive, as returned by
+/// [`list_archived_selectors`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ArchivedKey {
+    pub selector: String,
+    /// The TXT record, e.g. `v=DKIM1; k=rsa; p=...`. An empty `p=` marks a revoked key.
+    pub value: String,
     #[serde(rename = "firstSeenAt")]
-    _first_seen_at: DateTime<Utc>,
+    pub first_seen_at: DateTime<Utc>,
     #[serde(rename = "lastSeenAt")]
-    _last_seen_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// A source of DKIM public keys, tried in the order configured on a [`DkimResolver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsBackend {
+    /// Plain DNS against the resolver's nameservers, Google Public DNS (8.8.8.8) by default.
+    Google,
+    /// DNS-over-HTTPS against Cloudflare's JSON API.
+    Cloudflare,
+    /// The ZK Email archive of historical DKIM keys.
+    ZkArchive,
+    /// PEM files in the directory set with [`DkimResolver::with_local_store`], tried before every
+    /// other backend.
+    LocalStore,
+}
+
+/// Why a DKIM key could not be fetched, as returned by [`DkimResolver::fetch_dkim_key_typed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DkimKeyError {
+    /// The DNS query failed or returned an error status.
+    DnsFailed(String),
+    /// The ZK Email archive could not be queried or returned an unexpected response.
+    ArchiveFailed(String),
+    /// No key record exists for the selector.
+    SelectorNotFound,
+    /// The record's `k=` tag names a key type other than `rsa` or `ed25519`.
+    UnsupportedKeyType(String),
+    /// The record's `v=` tag is not `DKIM1`.
+    UnsupportedVersion(String),
+    /// The record's `p=` tag is empty, which marks the key as revoked (RFC 6376 section 3.6.1).
+    KeyRevoked,
+    /// The record's `p=` tag is not a valid key of its type.
+    InvalidKeyMaterial(String),
+    /// The record is in testing mode (`t=y`), so its key must not be trusted for verification.
+    TestingMode,
+}
+
+impl fmt::Display for DkimKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DnsFailed(e) => write!(f, "DNS lookup failed: {}", e),
+            Self::ArchiveFailed(e) => write!(f, "Archive lookup failed: {}", e),
+            Self::SelectorNotFound => write!(f, "No DKIM key found for the selector"),
+            Self::UnsupportedKeyType(key_type) => write!(f, "Unsupported key type: {}", key_type),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported key record version: {}", version)
+            }
+            Self::KeyRevoked => write!(f, "DKIM key has been revoked"),
+            Self::InvalidKeyMaterial(e) => write!(f, "Invalid key material: {}", e),
+            Self::TestingMode => write!(f, "DKIM key record is in testing mode (t=y)"),
+        }
+    }
+}
+
+impl std::error::Error for DkimKeyError {}
+
+/// A DNS-over-HTTPS JSON response (`application/dns-json`).
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+/// A parsed DKIM key record, as returned by [`DkimResolver::fetch_dkim_record`] and cached by
+/// the resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimDnsRecord {
+    /// The key bytes: PKCS#1 DER for RSA, raw bytes for Ed25519.
+    pub key: Vec<u8>,
+    /// The `k=` key type, `rsa` or `ed25519`.
+    pub key_type: String,
+    /// The flags of the `t=` tag, e.g. `y` and `s`.
+    pub flags: Vec<String>,
+}
+
+impl DkimDnsRecord {
+    pub fn is_rsa(&self) -> bool {
+        self.key_type == "rsa"
+    }
+
+    /// Whether the record is in testing mode (`t=y`), in which case verifiers must not treat a
+    /// signature differently from an unsigned email (RFC 6376 section 3.6.1).
+    pub fn is_testing(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "y")
+    }
+}
+
+/// Fetches DKIM public keys from a list of backends, by default Google DNS then the ZK Email
+/// archive, and caches successful lookups by selector and domain.
+#[derive(Debug)]
+pub struct DkimResolver {
+    client: Client,
+    backends: Vec<DnsBackend>,
+    cache: Mutex<HashMap<(String, String), (DkimDnsRecord, Instant)>>,
+    cache_ttl: Duration,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    max_archive_response_bytes: usize,
+    nameservers: Vec<IpAddr>,
+    local_store: Option<PathBuf>,
 }
 
+impl Default for DkimResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DkimResolver {
+    /// Creates a resolver whose cache keeps keys for five minutes.
+    ///
+    /// All resolvers share one HTTP client, and with it its connection pool and TLS
+    /// configuration, so creating a resolver per lookup is cheap.
+    pub fn new() -> Self {
+        Self {
+            client: shared_client().clone(),
+            backends: vec![DnsBackend::Google, DnsBackend::ZkArchive],
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl: DEFAULT_CACHE_TTL,
+            max_retries: 0,
+            retry_base_delay: Duration::ZERO,
+            max_archive_response_bytes: DEFAULT_MAX_ARCHIVE_RESPONSE_BYTES,
+            nameservers: Vec::new(),
+            local_store: None,
+        }
+    }
+
+    /// Uses `client` for the HTTP backends (Cloudflare and the ZK Email archive) instead of the
+    /// shared default client, e.g. to go through a proxy or trust custom root certificates.
+    ///
+    /// [`DnsBackend::Google`] queries DNS directly and does not use the client.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Sets the backends to query, in order. The first one to return a key wins.
+    pub fn with_backends(mut self, backends: Vec<DnsBackend>) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    /// Sets how long a fetched key is served from the cache. A zero TTL disables caching.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Retries each backend up to `max` more times on transient HTTP errors (timeouts, connection
+    /// failures, 429 and 5xx responses), waiting `base_delay` before the first retry and doubling
+    /// the delay after each one. A DNS error status in a successful response is not retried.
+    pub fn with_retries(mut self, max: u32, base_delay: Duration) -> Self {
+        self.max_retries = max;
+        self.retry_base_delay = base_delay;
+        self
+    }
+
+    /// Sets the nameservers queried by [`DnsBackend::Google`], e.g. an internal resolver. An
+    /// empty list falls back to 8.8.8.8.
+    pub fn with_nameservers(mut self, nameservers: Vec<IpAddr>) -> Self {
+        self.nameservers = nameservers;
+        self
+    }
+
+    /// Sets the largest ZK Email archive response that is read, 1 MiB by default. Larger
+    /// responses fail the archive lookup instead of being buffered.
+    pub fn with_max_archive_response_size(mut self, max_bytes: usize) -> Self {
+        self.max_archive_response_bytes = max_bytes;
+        self
+    }
+
+    /// Reads keys from `selector._domainkey.domain.pem` files in `path` before querying any
+    /// backend, e.g. to pin keys against rotation or to verify without network access.
+    ///
+    /// Files hold an RSA public key as `PUBLIC KEY` or `RSA PUBLIC KEY` PEM. Lookups only fall
+    /// through to the other backends when no file exists for the selector; an unreadable or
+    /// invalid file fails the lookup.
+    pub fn with_local_store(mut self, path: PathBuf) -> Self {
+        self.local_store = Some(path);
+        self
+    }
+
+    /// Drops all cached keys.
+    pub fn clear_cache(&self) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.clear();
+        }
+    }
+
+    /// Fetches the key for `selector._domainkey.domain`, returning the key bytes (PKCS#1 DER
+    /// for RSA, raw bytes for Ed25519) and the key type.
+    ///
+    /// Keys of records in testing mode (`t=y`) are rejected with [`DkimKeyError::TestingMode`];
+    /// use [`Self::fetch_dkim_record`] to inspect them.
+    pub async fn fetch_dkim_key(
+        &self,
+        logger: &Logger,
+        domain: &str,
+        selector: &str,
+    ) -> Result<(Vec<u8>, String)> {
+        let record = self.fetch_dkim_record(domain, selector).await?;
+        Ok(trusted_key(logger, domain, selector, record)?)
+    }
+
+    /// Same as [`Self::fetch_dkim_key`], returning the [`DkimKeyError`] of the last backend tried
+    /// so callers can tell why the key could not be fetched.
+    pub async fn fetch_dkim_key_typed(
+        &self,
+        logger: &Logger,
+        domain: &str,
+        selector: &str,
+    ) -> Result<(Vec<u8>, String), DkimKeyError> {
+        let record = self
+            .fetch_from_backends(domain, selector)
+            .await
+            .map_err(|mut failures| match failures.pop() {
+                Some((_, _, e)) => e,
+                None => DkimKeyError::DnsFailed("No DNS backends configured".to_string()),
+            })?;
+        trusted_key(logger, domain, selector, record)
+    }
+
+    /// Fetches and parses the key record of `selector._domainkey.domain` through the configured
+    /// backends, exposing its `k=` and `t=` tags along with the key.
+    ///
+    /// Only successful lookups are cached, so a transient failure is retried on the next call.
+    pub async fn fetch_dkim_record(&self, domain: &str, selector: &str) -> Result<DkimDnsRecord> {
+        self.fetch_from_backends(domain, selector)
+            .await
+            .map_err(|failures| {
+                let failures: Vec<String> = failures
+                    .iter()
+                    .map(|(backend, attempts, e)| {
+                        format!("{:?} after {} attempt(s): {}", backend, attempts, e)
+                    })
+                    .collect();
+                anyhow!(
+                    "Failed to fetch DKIM key for {}._domainkey.{} ({})",
+                    selector,
+                    domain,
+                    failures.join("; ")
+                )
+            })
+    }
+
+    /// Tries each backend in order, returning the first key record found or every backend's
+    /// failure with the number of attempts made.
+    ///
+    /// With the `tracing` feature, the lookup runs in a `debug` span and every attempt emits an
+    /// event with its backend, latency and outcome.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    async fn fetch_from_backends(
+        &self,
+        domain: &str,
+        selector: &str,
+    ) -> Result<DkimDnsRecord, Vec<(DnsBackend, u32, DkimKeyError)>> {
+        if let Some(record) = self.cached_record(domain, selector) {
+            return Ok(record);
+        }
+
+        let local_store = self.local_store.as_ref().map(|_| DnsBackend::LocalStore);
+        let mut failures = Vec::new();
+        for backend in local_store.into_iter().chain(self.backends.iter().copied()) {
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                #[cfg(feature = "tracing")]
+                let started = Instant::now();
+                let result = self.fetch_from_backend(backend, domain, selector).await;
+                #[cfg(feature = "tracing")]
+                trace_attempt(
+                    backend,
+                    domain,
+                    selector,
+                    attempts,
+                    started.elapsed(),
+                    &result,
+                );
+
+                match result {
+                    Ok(record) => {
+                        self.store_record(domain, selector, record.clone());
+                        return Ok(record);
+                    }
+                    Err(e) if attempts <= self.max_retries && is_transient(&e) => {
+                        tokio::time::sleep(backoff_delay(self.retry_base_delay, attempts)).await;
+                    }
+                    Err(e) => {
+                        let error = classify_error(backend, e);
+                        // A pinned key that cannot be read must not be replaced by a fetched one
+                        let pinned = backend == DnsBackend::LocalStore
+                            && error != DkimKeyError::SelectorNotFound;
+                        failures.push((backend, attempts, error));
+                        if pinned {
+                            return Err(failures);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        Err(failures)
+    }
+
+    /// Fetches the keys of several `(domain, selector)` pairs concurrently, with at most
+    /// `max_concurrent` lookups in flight. Results are returned in the order of `requests`.
+    pub async fn fetch_dkim_keys_batch(
+        &self,
+        requests: &[(String, String)],
+        max_concurrent: usize,
+    ) -> Vec<Result<(Vec<u8>, String)>> {
+        let logger = Logger::root(Discard, o!());
+        let mut results: Vec<(usize, Result<DkimKey>)> = stream::iter(requests.iter().enumerate())
+            .map(|(index, (domain, selector))| {
+                let logger = &logger;
+                async move { (index, self.fetch_dkim_key(logger, domain, selector).await) }
+            })
+            .buffer_unordered(max_concurrent.max(1))
+            .collect()
+            .await;
+
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Same as [`Self::fetch_dkim_key`], rejecting RSA keys whose modulus is shorter than
+    /// `min_bits`. Ed25519 keys have a fixed size and are always accepted.
+    pub async fn fetch_dkim_key_with_min_bits(
+        &self,
+        logger: &Logger,
+        domain: &str,
+        selector: &str,
+        min_bits: usize,
+    ) -> Result<(Vec<u8>, String)> {
+        let key = self.fetch_dkim_key(logger, domain, selector).await?;
+        check_min_key_bits(&key, min_bits)?;
+        Ok(key)
+    }
+
+    async fn fetch_from_backend(
+        &self,
+        backend: DnsBackend,
+        domain: &str,
+        selector: &str,
+    ) -> Result<DkimDnsRecord> {
+        match backend {
+            DnsBackend::Google => fetch_from_google_dns(&self.nameservers, domain, selector).await,
+            DnsBackend::Cloudflare => self.fetch_from_cloudflare(domain, selector).await,
+            DnsBackend::ZkArchive => self.fetch_from_zkemail_archive(domain, selector).await,
+            DnsBackend::LocalStore => Ok(self.fetch_from_local_store(domain, selector)?),
+        }
+    }
+
+    /// Reads the selector's PEM file from the local store.
+    fn fetch_from_local_store(
+        &self,
+        domain: &str,
+        selector: &str,
+    ) -> Result<DkimDnsRecord, DkimKeyError> {
+        let directory = self
+            .local_store
+            .as_ref()
+            .ok_or(DkimKeyError::SelectorNotFound)?;
+        let path = directory.join(format!(
+            "{}._domainkey.{}.pem",
+            selector,
+            domain.to_lowercase()
+        ));
+        let invalid = |e: &dyn fmt::Display| {
+            DkimKeyError::InvalidKeyMaterial(format!("{}: {}", path.display(), e))
+        };
+
+        let pem = match std::fs::read_to_string(&path) {
+            Ok(pem) => pem,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(DkimKeyError::SelectorNotFound)
+            }
+            Err(e) => return Err(invalid(&e)),
+        };
+        let key = parse_rsa_public_key(&pem)
+            .map_err(|e| invalid(&e))?
+            .to_pkcs1_der()
+            .map_err(|e| invalid(&e))?
+            .as_bytes()
+            .to_vec();
+        Ok(DkimDnsRecord {
+            key,
+            key_type: "rsa".to_string(),
+            flags: Vec::new(),
+        })
+    }
+
+    /// Queries Cloudflare's DNS-over-HTTPS JSON API for the selector's TXT record.
+    pub async fn fetch_from_cloudflare(
+        &self,
+        domain: &str,
+        selector: &str,
+    ) -> Result<DkimDnsRecord> {
+        let record = self
+            .fetch_txt_record(&format!("{}._domainkey.{}", selector, domain))
+            .await?
+            .ok_or(DkimKeyError::SelectorNotFound)?;
+
+        Ok(parse_dkim_dns_record(&record)?)
+    }
+
+    /// Looks up the TXT record of `name` over Cloudflare's DNS-over-HTTPS JSON API, returning
+    /// `None` when the name does not exist or has no TXT record.
+    pub(crate) async fn fetch_txt_record(&self, name: &str) -> Result<Option<String>> {
+        let response: DohResponse = self
+            .client
+            .get(CLOUDFLARE_DOH_API)
+            .query(&[("name", name), ("type", "TXT")])
+            .header(ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        match response.status {
+            0 => Ok(reassemble_txt_record(&response.answer)),
+            NXDOMAIN_STATUS => Ok(None),
+            status => Err(anyhow!("DNS query failed with status {}", status)),
+        }
+    }
+
+    /// Lists every key record the ZK Email archive has seen for `domain`, across all selectors,
+    /// e.g. to find the selector of an old email whose key has since been rotated out of DNS.
+    pub async fn list_archived_selectors(&self, domain: &str) -> Result<Vec<ArchivedKey>> {
+        let response = self
+            .client
+            .get(format!("{}/key?domain={}", ARCHIVE_API, domain))
+            .timeout(ARCHIVE_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        let body = read_capped(response, self.max_archive_response_bytes).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn fetch_from_zkemail_archive(
+        &self,
+        domain: &str,
+        selector: &str,
+    ) -> Result<DkimDnsRecord> {
+        let keys = self.list_archived_selectors(domain).await?;
+
+        // The archive keeps revoked records next to the keys they replaced, so only report
+        // KeyRevoked when no record of the selector has a usable key
+        Ok(parse_first_usable_record(
+            keys.iter()
+                .filter(|k| k.selector == selector)
+                .map(|k| k.value.as_str()),
+        )?)
+    }
+
+    fn cache_key(domain: &str, selector: &str) -> (String, String) {
+        (selector.to_string(), domain.to_lowercase())
+    }
+
+    fn cached_record(&self, domain: &str, selector: &str) -> Option<DkimDnsRecord> {
+        let cache = self.cache.lock().ok()?;
+        cache
+            .get(&Self::cache_key(domain, selector))
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < self.cache_ttl)
+            .map(|(record, _)| record.clone())
+    }
+
+    fn store_record(&self, domain: &str, selector: &str, record: DkimDnsRecord) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.insert(Self::cache_key(domain, selector), (record, Instant::now()));
+        }
+    }
+}
+
+fn shared_client() -> &'static Client {
+    SHARED_CLIENT.get_or_init(Client::new)
+}
+
+/// Returns the key of a fetched record, rejecting records in testing mode.
+fn trusted_key(
+    logger: &Logger,
+    domain: &str,
+    selector: &str,
+    record: DkimDnsRecord,
+) -> Result<DkimKey, DkimKeyError> {
+    if record.is_testing() {
+        slog::warn!(logger, "Rejecting DKIM key in testing mode";
+            "domain" => domain, "selector" => selector);
+        return Err(DkimKeyError::TestingMode);
+    }
+    Ok((record.key, record.key_type))
+}
+
+/// Fetches a DKIM public key with a fresh [`DkimResolver`].
 pub async fn fetch_dkim_key(
     logger: &Logger,
     domain: &str,
     selector: &str,
 ) -> Result<(Vec<u8>, String)> {
-    // Try DNS first
+    DkimResolver::new()
+        .fetch_dkim_key(logger, domain, selector)
+        .await
+}
+
+/// Same as [`fetch_dkim_key`], querying `nameservers` instead of 8.8.8.8 for plain DNS lookups.
+/// An empty list falls back to 8.8.8.8.
+pub async fn fetch_dkim_key_with_nameservers(
+    logger: &Logger,
+    domain: &str,
+    selector: &str,
+    nameservers: &[IpAddr],
+) -> Result<(Vec<u8>, String)> {
+    DkimResolver::new()
+        .with_nameservers(nameservers.to_vec())
+        .fetch_dkim_key(logger, domain, selector)
+        .await
+}
+
+/// Fetches the keys of several `(domain, selector)` pairs with a fresh [`DkimResolver`]; see
+/// [`DkimResolver::fetch_dkim_keys_batch`].
+pub async fn fetch_dkim_keys_batch(
+    requests: &[(String, String)],
+    max_concurrent: usize,
+) -> Vec<Result<(Vec<u8>, String)>> {
+    DkimResolver::new()
+        .fetch_dkim_keys_batch(requests, max_concurrent)
+        .await
+}
+
+/// Fetches a DKIM public key with a fresh [`DkimResolver`], returning a typed error.
+pub async fn fetch_dkim_key_typed(
+    logger: &Logger,
+    domain: &str,
+    selector: &str,
+) -> Result<(Vec<u8>, String), DkimKeyError> {
+    DkimResolver::new()
+        .fetch_dkim_key_typed(logger, domain, selector)
+        .await
+}
+
+/// Fetches and parses a DKIM key record with a fresh [`DkimResolver`]; see
+/// [`DkimResolver::fetch_dkim_record`].
+pub async fn fetch_dkim_record(domain: &str, selector: &str) -> Result<DkimDnsRecord> {
+    DkimResolver::new()
+        .fetch_dkim_record(domain, selector)
+        .await
+}
+
+/// Lists the archived key records of `domain` with a fresh [`DkimResolver`]; see
+/// [`DkimResolver::list_archived_selectors`].
+pub async fn list_archived_selectors(domain: &str) -> Result<Vec<ArchivedKey>> {
+    DkimResolver::new().list_archived_selectors(domain).await
+}
+
+/// Verifies an email's DKIM signature against the domain of its own `From` header instead of a
+/// caller-supplied domain.
+///
+/// Only signatures whose `d=` tag equals the `From` domain are considered, so the caller cannot
+/// steer verification towards an unrelated signer.
+///
+/// # Returns
+///
+/// The domain whose signature was verified.
+pub async fn verify_dkim_autodomain(raw_email: &[u8], resolver: &DkimResolver) -> Result<String> {
+    let email = find_verified_signature(raw_email, SigningDomain::From, None, resolver).await?;
+    Ok(email.from_domain)
+}
+
+async fn fetch_from_google_dns(
+    nameservers: &[IpAddr],
+    domain: &str,
+    selector: &str,
+) -> Result<DkimDnsRecord> {
+    let nameservers = if nameservers.is_empty() {
+        &[DEFAULT_NAMESERVER][..]
+    } else {
+        nameservers
+    };
     let resolver = TokioAsyncResolver::tokio(
         ResolverConfig::from_parts(
             None,
             vec![],
-            NameServerConfigGroup::from_ips_clear(&["8.8.8.8".parse()?], 53, true),
+            NameServerConfigGroup::from_ips_clear(nameservers, 53, true),
         ),
         ResolverOpts::default(),
     );
-    let resolver = from_tokio_resolver(resolver);
 
-    match retrieve_public_key(logger, resolver, domain.to_string(), selector.to_string()).await {
-        Ok(public_key) => match public_key {
-            DkimPublicKey::Rsa(rsa_key) => {
-                let key_bytes = rsa_key.to_pkcs1_der()?.as_bytes().to_vec();
-                Ok((key_bytes, "rsa".to_string()))
-            }
-            DkimPublicKey::Ed25519(ed_key) => {
-                let key_bytes = ed_key.to_bytes().to_vec();
-                Ok((key_bytes, "ed25519".to_string()))
-            }
-        },
-        Err(_) => {
-            // Fallback to archive
-            let keys: Vec<DkimKeyResponse> = Client::new()
-                .get(format!("{}/key?domain={}", ARCHIVE_API, domain))
-                .send()
-                .await?
-                .json()
-                .await?;
-
-            let key = keys
+    let lookup = match resolver
+        .txt_lookup(format!("{}._domainkey.{}", selector, domain))
+        .await
+    {
+        Ok(lookup) => lookup,
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+            return Err(DkimKeyError::SelectorNotFound.into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // A TXT record longer than 255 bytes is split into several character-strings
+    let records: Vec<String> = lookup
+        .iter()
+        .map(|txt| {
+            txt.txt_data()
                 .iter()
-                .find(|k| {
-                    k.selector == selector && k.value.contains("p=") && !k.value.ends_with("p=")
-                })
-                .ok_or_else(|| anyhow!("No valid DKIM key found"))?;
-
-            let (mut key_type, public_key) = key.value.split(';').map(str::trim).fold(
-                (String::new(), String::new()),
-                |(mut kt, mut pk), part| {
-                    if let Some(stripped) = part.strip_prefix("k=") {
-                        kt = stripped.to_string();
-                    }
-                    if let Some(stripped) = part.strip_prefix("p=") {
-                        pk = stripped.to_string();
-                    }
-                    (kt, pk)
-                },
-            );
+                .map(|data| String::from_utf8_lossy(data))
+                .collect()
+        })
+        .collect();
+    Ok(parse_first_usable_record(
+        records.iter().map(String::as_str),
+    )?)
+}
 
-            // defaults to rsa if no key type is found
-            if key_type.is_empty() {
-                key_type = "rsa".to_string();
-            }
+/// Reads a response body, failing as soon as it grows past `max_bytes` instead of buffering it
+/// whole.
+async fn read_capped(mut response: reqwest::Response, max_bytes: usize) -> Result<Vec<u8>> {
+    let too_large = || anyhow!("Response body exceeds {} bytes", max_bytes);
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_bytes as u64)
+    {
+        return Err(too_large());
+    }
 
-            if public_key.is_empty() {
-                return Err(anyhow!("No public key found"));
-            }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
 
-            let key_bytes = if key_type == "rsa" {
-                let decoded = STANDARD.decode(&public_key)?;
-                RsaPublicKey::from_public_key_der(&decoded)
-                    .or_else(|_| RsaPublicKey::from_pkcs1_der(&decoded))?
-                    .to_pkcs1_der()?
-                    .as_bytes()
-                    .to_vec()
-            } else if key_type == "ed25519" {
-                let decoded = STANDARD.decode(&public_key)?;
-                if decoded.len() != 32 {
-                    return Err(anyhow!("Invalid Ed25519 key length"));
-                }
-                decoded
-            } else {
-                return Err(anyhow!("Unsupported key type: {}", key_type));
-            };
+/// Emits a `debug` event for one backend attempt of [`DkimResolver::fetch_dkim_key`].
+#[cfg(feature = "tracing")]
+fn trace_attempt(
+    backend: DnsBackend,
+    domain: &str,
+    selector: &str,
+    attempt: u32,
+    latency: Duration,
+    result: &Result<DkimDnsRecord>,
+) {
+    let dns_name = format!("{}._domainkey.{}", selector, domain);
+    let latency_ms = latency.as_millis() as u64;
+    match result {
+        Ok(record) => tracing::debug!(
+            dns_name = %dns_name,
+            ?backend,
+            attempt,
+            latency_ms,
+            key_type = %record.key_type,
+            testing = record.is_testing(),
+            "DKIM key lookup succeeded"
+        ),
+        Err(e) => tracing::debug!(
+            dns_name = %dns_name,
+            ?backend,
+            attempt,
+            latency_ms,
+            error = %e,
+            "DKIM key lookup failed"
+        ),
+    }
+}
+
+/// Returns the delay before retry number `retry` (starting at 1): `base`, then doubling.
+fn backoff_delay(base: Duration, retry: u32) -> Duration {
+    base.saturating_mul(1 << (retry - 1).min(MAX_BACKOFF_SHIFT))
+}
+
+/// Whether a backend error is worth retrying: a timeout, a failed connection or a 429 or 5xx
+/// HTTP status.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+        e.is_timeout()
+            || e.is_connect()
+            || e.status().is_some_and(|status| {
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            })
+    })
+}
+
+/// Maps a backend error to a [`DkimKeyError`], keeping errors that are already typed.
+fn classify_error(backend: DnsBackend, error: anyhow::Error) -> DkimKeyError {
+    error
+        .downcast::<DkimKeyError>()
+        .unwrap_or_else(|e| match backend {
+            DnsBackend::ZkArchive => DkimKeyError::ArchiveFailed(e.to_string()),
+            DnsBackend::Google | DnsBackend::Cloudflare => DkimKeyError::DnsFailed(e.to_string()),
+            DnsBackend::LocalStore => DkimKeyError::InvalidKeyMaterial(e.to_string()),
+        })
+}
+
+fn check_min_key_bits((key_bytes, key_type): &DkimKey, min_bits: usize) -> Result<()> {
+    if key_type != "rsa" {
+        return Ok(());
+    }
+
+    let bits = rsa_key_bits(&RsaPublicKey::from_pkcs1_der(key_bytes)?);
+    if bits < min_bits {
+        return Err(anyhow!(
+            "RSA key is {} bits, below the minimum of {}",
+            bits,
+            min_bits
+        ));
+    }
+    Ok(())
+}
+
+/// Concatenates the TXT answers of a DNS-over-HTTPS response in order. Keys too long for a
+/// single 255-byte string are split by DNS into several fragments that must be joined.
+fn reassemble_txt_record(answers: &[DohAnswer]) -> Option<String> {
+    let fragments: Vec<String> = answers
+        .iter()
+        .filter(|answer| answer.record_type == TXT_RECORD_TYPE)
+        .map(|answer| unquote_txt_data(&answer.data))
+        .collect();
+
+    (!fragments.is_empty()).then(|| fragments.concat())
+}
 
-            Ok((key_bytes, key_type))
+/// Joins the quoted character-strings of a TXT `data` field, e.g. `"abc" "def"` into `abcdef`.
+fn unquote_txt_data(data: &str) -> String {
+    if !data.contains('"') {
+        return data.to_string();
+    }
+    data.split('"').skip(1).step_by(2).collect()
+}
+
+/// Parses the first record with a usable key, returning the error of the last record when none
+/// has one, or [`DkimKeyError::SelectorNotFound`] when there are no records.
+fn parse_first_usable_record<'a>(
+    records: impl IntoIterator<Item = &'a str>,
+) -> Result<DkimDnsRecord, DkimKeyError> {
+    let mut error = DkimKeyError::SelectorNotFound;
+    for record in records {
+        match parse_dkim_dns_record(record) {
+            Ok(record) => return Ok(record),
+            Err(e) => error = e,
         }
     }
+    Err(error)
+}
+
+/// Parses the tags of a DKIM key record (RFC 6376 section 3.6.1), e.g.
+/// `v=DKIM1; k=rsa; t=y; p=...`.
+///
+/// A `v=` tag other than `DKIM1` is rejected, an empty `p=` tag yields
+/// [`DkimKeyError::KeyRevoked`], and `k=` defaults to `rsa`. The key is returned
+/// as PKCS#1 DER for RSA and as the raw 32 bytes for Ed25519.
+pub fn parse_dkim_dns_record(record: &str) -> Result<DkimDnsRecord, DkimKeyError> {
+    let tags = parse_dkim_tags(record);
+
+    if let Some(version) = tags.get("v").filter(|version| *version != "DKIM1") {
+        return Err(DkimKeyError::UnsupportedVersion(version.clone()));
+    }
+    // defaults to rsa if no key type is found
+    let key_type = tags
+        .get("k")
+        .filter(|key_type| !key_type.is_empty())
+        .map_or("rsa", String::as_str)
+        .to_string();
+    let flags: Vec<String> = tags
+        .get("t")
+        .map(|flags| {
+            flags
+                .split(':')
+                .map(str::trim)
+                .filter(|flag| !flag.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let public_key = match tags.get("p") {
+        Some(public_key) if public_key.is_empty() => return Err(DkimKeyError::KeyRevoked),
+        Some(public_key) => public_key.as_str(),
+        None => {
+            return Err(DkimKeyError::InvalidKeyMaterial(
+                "No public key found".to_string(),
+            ))
+        }
+    };
+
+    let invalid = |e: &dyn fmt::Display| DkimKeyError::InvalidKeyMaterial(e.to_string());
+    let key = if key_type == "rsa" {
+        parse_rsa_public_key(public_key)
+            .map_err(|e| invalid(&e))?
+            .to_pkcs1_der()
+            .map_err(|e| invalid(&e))?
+            .as_bytes()
+            .to_vec()
+    } else if key_type == "ed25519" {
+        let decoded = STANDARD.decode(public_key).map_err(|e| invalid(&e))?;
+        if decoded.len() != 32 {
+            return Err(invalid(&"Invalid Ed25519 key length"));
+        }
+        decoded
+    } else {
+        return Err(DkimKeyError::UnsupportedKeyType(key_type));
+    };
+
+    Ok(DkimDnsRecord {
+        key,
+        key_type,
+        flags,
+    })
 }
 
 #[cfg(test)]
@@ -126,6 +894,18 @@ mod tests {
         root
     }
 
+    fn rsa_record(key: Vec<u8>) -> DkimDnsRecord {
+        DkimDnsRecord {
+            key,
+            key_type: "rsa".to_string(),
+            flags: Vec::new(),
+        }
+    }
+
+    fn parse_key(record: &str) -> Result<DkimKey, DkimKeyError> {
+        parse_dkim_dns_record(record).map(|record| (record.key, record.key_type))
+    }
+
     #[tokio::test]
     async fn test_fetch_dkim_key_from_archive() {
         let logger = create_logger();
@@ -143,4 +923,274 @@ mod tests {
         assert!(!key_bytes.is_empty(), "key bytes should not be empty");
         assert_eq!(key_type, "rsa", "key type should be rsa for cryptoradar");
     }
+
+    #[tokio::test]
+    async fn test_list_archived_selectors() {
+        let keys = list_archived_selectors("cryptoradar.com").await.unwrap();
+        let key = keys
+            .iter()
+            .find(|key| key.selector == "ez5fdfeqyxjjof6psrzjbiqfmtoen2xs")
+            .expect("archive should list the cryptoradar selector");
+        assert!(key.value.contains("p="));
+        assert!(key.first_seen_at <= key.last_seen_at);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_dkim_key_uses_cache() {
+        let logger = create_logger();
+        let resolver = DkimResolver::new();
+        resolver.store_record("Example.invalid", "selector", rsa_record(vec![1, 2, 3]));
+
+        let result = resolver
+            .fetch_dkim_key(&logger, "example.invalid", "selector")
+            .await;
+        assert_eq!(result.unwrap(), (vec![1, 2, 3], "rsa".to_string()));
+
+        resolver.clear_cache();
+        assert!(resolver
+            .cached_record("example.invalid", "selector")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_dkim_key_rejects_testing_records() {
+        let logger = create_logger();
+        let resolver = DkimResolver::new().with_backends(vec![]);
+        let record = DkimDnsRecord {
+            flags: vec!["y".to_string()],
+            ..rsa_record(vec![1])
+        };
+        resolver.store_record("example.invalid", "selector", record.clone());
+
+        assert_eq!(
+            resolver
+                .fetch_dkim_key_typed(&logger, "example.invalid", "selector")
+                .await,
+            Err(DkimKeyError::TestingMode)
+        );
+        assert!(resolver
+            .fetch_dkim_key(&logger, "example.invalid", "selector")
+            .await
+            .is_err());
+        assert_eq!(
+            resolver
+                .fetch_dkim_record("example.invalid", "selector")
+                .await
+                .unwrap(),
+            record
+        );
+    }
+
+    #[test]
+    fn test_reassemble_split_txt_record() {
+        let response: DohResponse = serde_json::from_str(
+            r#"{
+                "Status": 0,
+                "Answer": [
+                    {"name": "s._domainkey.example.com", "type": 5, "data": "alias.example.com."},
+                    {"name": "s._domainkey.example.com", "type": 16,
+                     "data": "\"v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC0mjmfxTQ6LNy2W95+AlS+\" \"N5w7mDNd+NsnwIJtftyaOU5PEvBtW9vT8Lfr0evPzi3OkbzZESVP+9mXkEyLnmIonxZT/l\""},
+                    {"name": "s._domainkey.example.com", "type": 16, "data": "\"imx/lViUc6WIJETPmX025kRY+jaFDD3a8XdHrxoa1Oxd2GRcUdvZf0CGwrUnGX+l6izE5wibw2I8RQ3wIDAQAB\""}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let record = reassemble_txt_record(&response.answer).unwrap();
+        let (key_bytes, key_type) = parse_key(&record).unwrap();
+        assert_eq!(key_type, "rsa");
+        assert!(!key_bytes.is_empty());
+    }
+
+    #[test]
+    fn test_parse_dkim_dns_record() {
+        let key = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+        let record =
+            parse_dkim_dns_record(&format!("v=DKIM1; k=ed25519; t=y:s; p={}", key)).unwrap();
+        assert_eq!(record.key_type, "ed25519");
+        assert_eq!(record.key.len(), 32);
+        assert!(!record.is_rsa());
+        assert!(record.is_testing());
+        assert_eq!(record.flags, vec!["y", "s"]);
+
+        let record = parse_dkim_dns_record(&format!("k=ed25519; t=s; p={}", key)).unwrap();
+        assert!(!record.is_testing());
+        assert_eq!(
+            parse_dkim_dns_record(&format!("v=DKIM2; k=ed25519; p={}", key)),
+            Err(DkimKeyError::UnsupportedVersion("DKIM2".to_string()))
+        );
+        assert_eq!(
+            parse_dkim_dns_record("v=DKIM1; k=rsa; p="),
+            Err(DkimKeyError::KeyRevoked)
+        );
+        assert!(matches!(
+            parse_dkim_dns_record("v=DKIM1; k=rsa"),
+            Err(DkimKeyError::InvalidKeyMaterial(_))
+        ));
+    }
+
+    #[test]
+    fn test_cache_respects_ttl() {
+        let resolver = DkimResolver::new().with_cache_ttl(Duration::ZERO);
+        resolver.store_record("example.invalid", "selector", rsa_record(vec![1]));
+        assert!(resolver
+            .cached_record("example.invalid", "selector")
+            .is_none());
+    }
+
+    #[test]
+    fn test_check_min_key_bits() {
+        let key_512 = parse_key(
+            "v=DKIM1; k=rsa; p=MFwwDQYJKoZIhvcNAQEBBQADSwAwSAJBAMfNjWfT9pb20Iki41NjLcGLV5Kxm1IEpj0pquGDfBFBG075dWtaKB1IS5oGq8vFtdTeVf8kujALfQVo1UJZriMCAwEAAQ==",
+        )
+        .unwrap();
+        let key_2048 = parse_key(
+            "v=DKIM1; k=rsa; p=MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAqW+N0CRSO7zdyWsAvWOFcxAU7fkz3LlnJXTmvRd/gNdnlCKLTUqxskwu6EB50VRAwSSjbXYwRAI+n3pPo8/bsLSdev8jtViCdRzSfO370xml/eT2+uqSVXtwl5hdfohZLyObGnC1xG7Ts6+yZPIOyQrOdnAQKsHMUPmqAb156HQwIJ2SLHULheyJFX0KMHRU+xJdumDNnCS9IjruaQeM7hX5WXb2Ujx+AfEjYgeGgh1RXgrERShzuNC9PN6DuezE4gCFZU4JFvWWqcoHp/yieJQgvxHnlYcU3uE6GNcNR1gn+F5GiPfgZfxR/IHQmTTuJe5l1ymTEJjbIxu/F3B9mwIDAQAB",
+        )
+        .unwrap();
+
+        assert!(check_min_key_bits(&key_512, 1024).is_err());
+        assert!(check_min_key_bits(&key_2048, 1024).is_ok());
+        assert!(check_min_key_bits(&(vec![0; 32], "ed25519".to_string()), 1024).is_ok());
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let base = Duration::from_millis(100);
+        assert_eq!(backoff_delay(base, 1), base);
+        assert_eq!(backoff_delay(base, 3), Duration::from_millis(400));
+        assert!(!is_transient(&anyhow!("DNS query failed with status 3")));
+    }
+
+    #[test]
+    fn test_dkim_key_errors() {
+        assert_eq!(
+            parse_key("v=DKIM1; k=ed448; p=AAAA"),
+            Err(DkimKeyError::UnsupportedKeyType("ed448".to_string()))
+        );
+        assert!(matches!(
+            parse_key("v=DKIM1; k=rsa; p=not-base64!"),
+            Err(DkimKeyError::InvalidKeyMaterial(_))
+        ));
+        assert_eq!(
+            classify_error(DnsBackend::ZkArchive, DkimKeyError::SelectorNotFound.into()),
+            DkimKeyError::SelectorNotFound
+        );
+        assert_eq!(
+            classify_error(DnsBackend::ZkArchive, anyhow!("timed out")),
+            DkimKeyError::ArchiveFailed("timed out".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_dkim_key_from_local_store() {
+        let logger = create_logger();
+        let store = std::env::temp_dir().join(format!("zkemail-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(
+            store.join("s1._domainkey.example.invalid.pem"),
+            "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQCWgp1mc6Bu1dERUJjOjOCR25Cp
+iXg38+e52/U/Sn+6nYRYbyac96kHacWBw9YzlVA3mr/CL0ABgyQxOWQrJkYZrah5
+NwHdxf91CdD+94F7d8JAZtie6uO83U44EUEqOVxB8721n+Vlue0CSdlR0jaxtdx9
+SHQe4ccLy44+q42wNwIDAQAB
+-----END PUBLIC KEY-----
+",
+        )
+        .unwrap();
+        std::fs::write(
+            store.join("bad._domainkey.example.invalid.pem"),
+            "not a key",
+        )
+        .unwrap();
+
+        let resolver = DkimResolver::new()
+            .with_backends(vec![])
+            .with_cache_ttl(Duration::ZERO)
+            .with_local_store(store.clone());
+        let (key_bytes, key_type) = resolver
+            .fetch_dkim_key(&logger, "Example.invalid", "s1")
+            .await
+            .unwrap();
+        assert_eq!(key_type, "rsa");
+        assert_eq!(
+            rsa_key_bits(&RsaPublicKey::from_pkcs1_der(&key_bytes).unwrap()),
+            1024
+        );
+
+        assert_eq!(
+            resolver
+                .fetch_dkim_key_typed(&logger, "example.invalid", "missing")
+                .await,
+            Err(DkimKeyError::SelectorNotFound)
+        );
+
+        // An invalid pinned key is not replaced by a key from the network
+        let resolver = resolver.with_backends(vec![DnsBackend::ZkArchive]);
+        assert!(matches!(
+            resolver
+                .fetch_dkim_key_typed(&logger, "example.invalid", "bad")
+                .await,
+            Err(DkimKeyError::InvalidKeyMaterial(_))
+        ));
+
+        std::fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_dkim_keys_batch_preserves_order() {
+        let resolver = DkimResolver::new().with_backends(vec![]);
+        resolver.store_record("a.invalid", "s1", rsa_record(vec![1]));
+        resolver.store_record("b.invalid", "s2", rsa_record(vec![2]));
+        let request = |domain: &str, selector: &str| (domain.to_string(), selector.to_string());
+
+        let results = resolver
+            .fetch_dkim_keys_batch(
+                &[
+                    request("b.invalid", "s2"),
+                    request("c.invalid", "s3"),
+                    request("a.invalid", "s1"),
+                ],
+                2,
+            )
+            .await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().0, vec![2]);
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap().0, vec![1]);
+    }
+
+    /// Serves a single HTTP response on a local port and returns its URL.
+    fn serve_once(response: Vec<u8>) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let _ = stream.read(&mut [0; 1024]);
+                let _ = stream.write_all(&response);
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_read_capped() {
+        let body = |length: usize| {
+            let mut response = b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec();
+            response.extend(vec![b'a'; length]);
+            response
+        };
+        let fetch = |response: Vec<u8>| async move {
+            let response = shared_client().get(serve_once(response)).send().await?;
+            read_capped(response, 1024).await
+        };
+
+        assert_eq!(fetch(body(1024)).await.unwrap().len(), 1024);
+        assert!(fetch(body(64 * 1024)).await.is_err());
+
+        let declared = b"HTTP/1.1 200 OK\r\nContent-Length: 4096\r\n\r\n".to_vec();
+        assert!(fetch(declared).await.is_err());
+    }
 }
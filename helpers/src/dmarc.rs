@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Result};
+
+use crate::DkimResolver;
+
+/// What a DMARC policy asks receivers to do with mail that fails DMARC (the `p=` tag).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmarcDisposition {
+    None,
+    Quarantine,
+    Reject,
+}
+
+/// How closely the DKIM `d=` domain must match the `From` domain (the `adkim=` tag).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AlignmentMode {
+    /// The domains must share an organizational domain (`adkim=r`, the default).
+    #[default]
+    Relaxed,
+    /// The domains must be identical (`adkim=s`).
+    Strict,
+}
+
+/// The parts of a `_dmarc.<domain>` TXT record relevant to DKIM alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmarcPolicy {
+    pub disposition: DmarcDisposition,
+    pub dkim_alignment: AlignmentMode,
+}
+
+/// Fetches and parses the DMARC policy published at `_dmarc.<domain>`.
+///
+/// The record is looked up over DNS-over-HTTPS with the resolver's HTTP client, regardless of
+/// its configured DKIM backends. Only the exact domain is queried; falling back to the
+/// organizational domain's record is left to the caller.
+pub async fn fetch_dmarc_policy(domain: &str, resolver: &DkimResolver) -> Result<DmarcPolicy> {
+    let record = resolver
+        .fetch_txt_record(&format!("_dmarc.{}", domain))
+        .await?
+        .ok_or_else(|| anyhow!("No DMARC record found for {}", domain))?;
+
+    parse_dmarc_record(&record)
+}
+
+/// Whether a DKIM signing domain is aligned with the `From` domain under `mode`.
+///
+/// Relaxed alignment compares organizational domains, the registrable part of each domain as
+/// determined by the public suffix list, so `a.example.com` and `b.example.com` are aligned but
+/// `evil.duckdns.org` and `duckdns.org` are not. Domains that are themselves public suffixes
+/// only align with an identical domain.
+pub fn check_alignment(from_domain: &str, dkim_domain: &str, mode: AlignmentMode) -> bool {
+    let normalize = |domain: &str| domain.trim_end_matches('.').to_lowercase();
+    let (from_domain, dkim_domain) = (normalize(from_domain), normalize(dkim_domain));
+    if from_domain.is_empty() || dkim_domain.is_empty() {
+        return false;
+    }
+
+    match mode {
+        AlignmentMode::Strict => from_domain == dkim_domain,
+        AlignmentMode::Relaxed => {
+            from_domain == dkim_domain
+                || psl::domain_str(&from_domain)
+                    .is_some_and(|domain| psl::domain_str(&dkim_domain) == Some(domain))
+        }
+    }
+}
+
+/// Parses a DMARC record (`v=DMARC1; p=reject; adkim=s`), which must start with `v=DMARC1` and
+/// carry a `p=` tag.
+fn parse_dmarc_record(record: &str) -> Result<DmarcPolicy> {
+    let mut tags = record.split(';').map(str::trim).filter(|t| !t.is_empty());
+    if tags.next().map(|v| v.replace(' ', "")) != Some("v=DMARC1".to_string()) {
+        return Err(anyhow!("Not a DMARC record: {}", record));
+    }
+
+    let mut disposition = None;
+    let mut dkim_alignment = AlignmentMode::default();
+    for tag in tags {
+        let Some((name, value)) = tag.split_once('=') else {
+            continue;
+        };
+        match (name.trim(), value.trim().to_lowercase().as_str()) {
+            ("p", "none") => disposition = Some(DmarcDisposition::None),
+            ("p", "quarantine") => disposition = Some(DmarcDisposition::Quarantine),
+            ("p", "reject") => disposition = Some(DmarcDisposition::Reject),
+            ("p", other) => return Err(anyhow!("Invalid DMARC policy: {}", other)),
+            ("adkim", "r") => dkim_alignment = AlignmentMode::Relaxed,
+            ("adkim", "s") => dkim_alignment = AlignmentMode::Strict,
+            ("adkim", other) => return Err(anyhow!("Invalid DMARC adkim mode: {}", other)),
+            _ => {}
+        }
+    }
+
+    Ok(DmarcPolicy {
+        disposition: disposition.ok_or_else(|| anyhow!("DMARC record has no p= tag"))?,
+        dkim_alignment,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dmarc_record() {
+        assert_eq!(
+            parse_dmarc_record("v=DMARC1; p=reject; adkim=s; rua=mailto:d@example.com").unwrap(),
+            DmarcPolicy {
+                disposition: DmarcDisposition::Reject,
+                dkim_alignment: AlignmentMode::Strict,
+            }
+        );
+        assert_eq!(
+            parse_dmarc_record("v=DMARC1;p=none")
+                .unwrap()
+                .dkim_alignment,
+            AlignmentMode::Relaxed
+        );
+        assert!(parse_dmarc_record("v=DMARC1; adkim=s").is_err());
+        assert!(parse_dmarc_record("v=spf1 -all").is_err());
+    }
+
+    #[test]
+    fn test_check_alignment() {
+        use AlignmentMode::*;
+
+        assert!(check_alignment("Example.com", "example.com.", Strict));
+        assert!(!check_alignment("mail.example.com", "example.com", Strict));
+        assert!(check_alignment("mail.example.com", "example.com", Relaxed));
+        assert!(check_alignment("example.com", "mail.example.com", Relaxed));
+        assert!(!check_alignment("badexample.com", "example.com", Relaxed));
+        assert!(!check_alignment("example.com", "example.net", Relaxed));
+        assert!(check_alignment("a.example.com", "b.example.com", Relaxed));
+        assert!(check_alignment(
+            "example.co.uk",
+            "mail.example.co.uk",
+            Relaxed
+        ));
+        assert!(!check_alignment("a.co.uk", "b.co.uk", Relaxed));
+
+        // A public suffix is not an organizational domain
+        assert!(!check_alignment("duckdns.org", "evil.duckdns.org", Relaxed));
+        assert!(!check_alignment(
+            "victim.duckdns.org",
+            "evil.duckdns.org",
+            Relaxed
+        ));
+        assert!(check_alignment("duckdns.org", "duckdns.org", Relaxed));
+    }
+}
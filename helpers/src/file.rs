@@ -1,5 +1,8 @@
 use anyhow::{anyhow, Result};
-use std::{fs::File, io::BufReader, io::Read, path::PathBuf};
+use memmap2::Mmap;
+use std::{fs::File, io::BufReader, io::Read, ops::Deref, path::PathBuf};
+
+use crate::RegexConfig;
 
 pub fn read_email_file(path: &PathBuf) -> Result<Vec<u8>> {
     let file = File::open(path).map_err(|e| anyhow!("Failed to open email file: {}", e))?;
@@ -11,6 +14,46 @@ pub fn read_email_file(path: &PathBuf) -> Result<Vec<u8>> {
     Ok(contents)
 }
 
+/// The contents of an email file read by [`read_email_file_mmap`], dereferencing to its bytes.
+#[derive(Debug)]
+pub enum EmailFile {
+    /// The file is memory-mapped.
+    Mapped(Mmap),
+    /// The file could not be mapped and was read into memory instead.
+    Buffered(Vec<u8>),
+}
+
+impl Deref for EmailFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped(mmap) => &mmap[..],
+            Self::Buffered(contents) => contents,
+        }
+    }
+}
+
+/// Memory-maps an email file, so large emails can be parsed with `parse_mail` without first
+/// being read into a buffer. [`crate::generate_email_inputs`] still copies the email once, into
+/// the returned [`zkemail_core::Email`].
+///
+/// Falls back to [`read_email_file`] when the file cannot be mapped on this platform.
+///
+/// # Safety
+///
+/// The file must not be modified or truncated, by this or any other process, while the returned
+/// [`EmailFile`] is alive. Otherwise its bytes may change under shared references, which is
+/// undefined behavior, or accessing them may crash the process.
+pub unsafe fn read_email_file_mmap(path: &PathBuf) -> Result<EmailFile> {
+    let file = File::open(path).map_err(|e| anyhow!("Failed to open email file: {}", e))?;
+    // SAFETY: the caller guarantees that the file is not modified while it is mapped
+    match unsafe { Mmap::map(&file) } {
+        Ok(mmap) => Ok(EmailFile::Mapped(mmap)),
+        Err(_) => read_email_file(path).map(EmailFile::Buffered),
+    }
+}
+
 pub fn read_json_file<T>(path: &PathBuf) -> Result<T>
 where
     T: serde::de::DeserializeOwned,
@@ -21,3 +64,33 @@ where
     serde_json::from_reader(file)
         .map_err(|e| anyhow!("Failed to parse JSON from {}: {}", path.display(), e))
 }
+
+/// Reads a [`RegexConfig`] from a JSON file and validates it with [`RegexConfig::validate`], so
+/// config errors surface before any email is processed.
+pub fn read_regex_config(path: &PathBuf) -> Result<RegexConfig> {
+    let config: RegexConfig = read_json_file(path)?;
+    config
+        .validate()
+        .map_err(|e| anyhow!("Invalid regex config {}: {}", path.display(), e))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_email_file_mmap() {
+        let path = std::env::temp_dir().join(format!("zkemail-mmap-{}.eml", std::process::id()));
+        std::fs::write(&path, b"From: alice@example.com\r\n\r\nHello\r\n").unwrap();
+
+        // SAFETY: the file is not modified until the mapping is dropped
+        let mapped = unsafe { read_email_file_mmap(&path) }.unwrap();
+        assert_eq!(&*mapped, read_email_file(&path).unwrap().as_slice());
+        assert!(mailparse::parse_mail(&mapped).is_ok());
+        drop(mapped);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(unsafe { read_email_file_mmap(&path) }.is_err());
+    }
+}
@@ -1,55 +1,175 @@
+use std::net::IpAddr;
+
 use anyhow::{anyhow, Result};
-use cfdkim::{canonicalize_signed_email, validate_header, verify_email_with_key, DkimPublicKey};
-use mailparse::MailHeaderMap;
 use slog::{o, Discard, Logger};
 use zkemail_core::{
-    remove_quoted_printable_soft_breaks, Email, EmailWithRegex, ExternalInput, PublicKey, RegexInfo,
+    extract_all_dkim_fields, extract_from_domain, regex_match_inputs, try_verify_email,
+    verify_dkim_detail, Email, EmailVerifierOutput, EmailWithRegex, ExternalInput, PublicKey,
+    RegexInfo,
 };
 
-use crate::{dkim::fetch_dkim_key, regex::compile_regex_parts, RegexConfig};
+use crate::{regex::compile_regex_parts, DkimResolver, RegexConfig};
 
 pub async fn generate_email_inputs(
     from_domain: &str,
     raw_email: &[u8],
     external_inputs: Option<Vec<ExternalInput>>,
+) -> Result<Email> {
+    find_verified_signature(
+        raw_email,
+        SigningDomain::Exact(from_domain),
+        external_inputs,
+        &DkimResolver::new(),
+    )
+    .await
+}
+
+/// Same as [`generate_email_inputs`], querying `nameservers` instead of 8.8.8.8 when fetching the
+/// DKIM key over plain DNS. An empty list falls back to 8.8.8.8.
+pub async fn generate_email_inputs_with_nameservers(
+    from_domain: &str,
+    raw_email: &[u8],
+    external_inputs: Option<Vec<ExternalInput>>,
+    nameservers: &[IpAddr],
+) -> Result<Email> {
+    let resolver = DkimResolver::new().with_nameservers(nameservers.to_vec());
+    find_verified_signature(
+        raw_email,
+        SigningDomain::Exact(from_domain),
+        external_inputs,
+        &resolver,
+    )
+    .await
+}
+
+/// Same as [`generate_email_inputs`] for an email whose signing domain is not known up front.
+///
+/// The `d=` domain of the first signature whose key can be fetched and verifies the email is
+/// used, lowercased, and returned as the `from_domain` of the resulting [`Email`]. Signatures
+/// aligned with the `From` domain are tried first.
+pub async fn generate_email_inputs_auto(
+    raw_email: &[u8],
+    external_inputs: Option<Vec<ExternalInput>>,
+) -> Result<Email> {
+    find_verified_signature(
+        raw_email,
+        SigningDomain::Any,
+        external_inputs,
+        &DkimResolver::new(),
+    )
+    .await
+}
+
+/// Fetches the DKIM key of a raw email, verifies its signature and body, and returns the
+/// committed output, as a guest running [`zkemail_core::verify_email`] would.
+///
+/// The signing domain is detected as in [`generate_email_inputs_auto`] and no external inputs
+/// are committed.
+pub async fn verify_email_full(eml_content: &[u8]) -> Result<EmailVerifierOutput> {
+    verify_email_full_with_resolver(eml_content, &DkimResolver::new()).await
+}
+
+/// Same as [`verify_email_full`], fetching the key with `resolver`.
+pub async fn verify_email_full_with_resolver(
+    eml_content: &[u8],
+    resolver: &DkimResolver,
+) -> Result<EmailVerifierOutput> {
+    let email = find_verified_signature(eml_content, SigningDomain::Any, None, resolver).await?;
+    Ok(try_verify_email(&email)?)
+}
+
+/// The DKIM signatures [`find_verified_signature`] may pick its domain and key from.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SigningDomain<'a> {
+    /// Only signatures whose `d=` tag is this domain, compared case-insensitively.
+    Exact(&'a str),
+    /// Only signatures whose `d=` tag is the domain of the `From` header.
+    From,
+    /// Any signature, trying those aligned with the `From` domain first.
+    Any,
+}
+
+/// Tries each DKIM signature allowed by `signing_domain`, in the order of
+/// [`signature_candidates`], and builds the [`Email`] for the first one whose key can be fetched
+/// and verifies the email.
+pub(crate) async fn find_verified_signature(
+    raw_email: &[u8],
+    signing_domain: SigningDomain<'_>,
+    external_inputs: Option<Vec<ExternalInput>>,
+    resolver: &DkimResolver,
 ) -> Result<Email> {
     let logger = Logger::root(Discard, o!());
-    let email = mailparse::parse_mail(raw_email)?;
 
-    let dkim_headers = email.headers.get_all_headers("DKIM-Signature");
-    if dkim_headers.is_empty() {
-        return Err(anyhow!("No DKIM signatures found"));
+    // The raw email is copied once, into the `Email` reused for every signature tried
+    let mut email: Option<Email> = None;
+    for (domain, selector) in signature_candidates(raw_email, signing_domain)? {
+        let (key, key_type) = match resolver.fetch_dkim_key(&logger, &domain, &selector).await {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        let public_key = PublicKey { key, key_type };
+        let candidate = match email.take() {
+            Some(email) => Email {
+                from_domain: domain,
+                public_key,
+                ..email
+            },
+            None => Email {
+                from_domain: domain,
+                raw_email: raw_email.to_vec(),
+                public_key,
+                external_inputs: Vec::new(),
+            },
+        };
+        if verify_dkim_detail(&candidate, &logger).is_ok_and(|detail| detail.starts_with("pass")) {
+            return Ok(Email {
+                external_inputs: external_inputs.unwrap_or_default(),
+                ..candidate
+            });
+        }
+        email = Some(candidate);
     }
 
-    for header in dkim_headers.iter() {
-        let dkim_header = match validate_header(&String::from_utf8_lossy(header.get_value_raw())) {
-            Ok(h) if h.get_required_tag("d").to_lowercase() == from_domain.to_lowercase() => h,
-            _ => {
-                continue;
-            }
-        };
+    Err(anyhow!("No valid DKIM key found for any signature"))
+}
+
+/// Returns the `(domain, selector)` pairs of the DKIM signatures allowed by `signing_domain`, in
+/// the order they are tried: header order, with signatures aligned with the `From` domain moved
+/// first for [`SigningDomain::Any`]. Domains are lowercased, except an exact domain which is
+/// returned as given.
+fn signature_candidates(
+    raw_email: &[u8],
+    signing_domain: SigningDomain<'_>,
+) -> Result<Vec<(String, String)>> {
+    let signatures = extract_all_dkim_fields(raw_email)?;
+    if signatures.is_empty() {
+        return Err(anyhow!("No DKIM signatures found"));
+    }
+    let mut candidates: Vec<(String, String)> = signatures
+        .into_iter()
+        .filter_map(|mut fields| Some((fields.remove("d")?.to_lowercase(), fields.remove("s")?)))
+        .collect();
 
-        let selector = dkim_header.get_required_tag("s");
-        if let Ok((key, key_type)) = fetch_dkim_key(&logger, from_domain, &selector).await {
-            if let Ok(public_key) = DkimPublicKey::try_from_bytes(&key, &key_type) {
-                // TODO: Add ignore body hash feature and remove hardcoded false
-                if let Ok(result) =
-                    verify_email_with_key(&logger, from_domain, &email, public_key, false)
-                {
-                    if result.with_detail().starts_with("pass") {
-                        return Ok(Email {
-                            from_domain: from_domain.to_string(),
-                            raw_email: raw_email.to_vec(),
-                            public_key: PublicKey { key, key_type },
-                            external_inputs: external_inputs.unwrap_or_default(),
-                        });
-                    }
-                }
+    let parse_from_domain = || -> Result<String> {
+        Ok(extract_from_domain(&mailparse::parse_mail(raw_email)?)?.to_lowercase())
+    };
+    match signing_domain {
+        SigningDomain::Exact(domain) => {
+            candidates.retain(|(candidate, _)| candidate.eq_ignore_ascii_case(domain));
+            for (candidate, _) in candidates.iter_mut() {
+                *candidate = domain.to_string();
             }
         }
+        SigningDomain::From => {
+            let from_domain = parse_from_domain()?;
+            candidates.retain(|(candidate, _)| *candidate == from_domain);
+        }
+        SigningDomain::Any => {
+            let from_domain = parse_from_domain().ok();
+            candidates.sort_by_key(|(candidate, _)| Some(candidate) != from_domain.as_ref());
+        }
     }
-
-    Err(anyhow!("No valid DKIM key found for any signature"))
+    Ok(candidates)
 }
 
 pub async fn generate_email_with_regex_inputs(
@@ -60,21 +180,23 @@ pub async fn generate_email_with_regex_inputs(
 ) -> Result<EmailWithRegex> {
     let email_inputs = generate_email_inputs(from_domain, raw_email, external_inputs).await?;
 
-    let (canonicalized_header, canonicalized_body, _) = canonicalize_signed_email(raw_email)?;
-
-    let (cleaned_body, _) = remove_quoted_printable_soft_breaks(canonicalized_body);
+    let (header_input, body_input) = regex_match_inputs(
+        &email_inputs,
+        regex_config.header_input,
+        regex_config.match_target,
+    )?;
 
     let body_parts = regex_config
         .body_parts
         .as_ref()
         .filter(|parts| !parts.is_empty())
-        .map(|parts| compile_regex_parts(parts, &cleaned_body))
+        .map(|parts| compile_regex_parts(parts, &body_input))
         .transpose()?;
     let header_parts = regex_config
         .header_parts
         .as_ref()
         .filter(|parts| !parts.is_empty())
-        .map(|parts| compile_regex_parts(parts, &canonicalized_header))
+        .map(|parts| compile_regex_parts(parts, &header_input))
         .transpose()?;
 
     Ok(EmailWithRegex {
@@ -82,6 +204,108 @@ pub async fn generate_email_with_regex_inputs(
         regex_info: RegexInfo {
             header_parts,
             body_parts,
+            header_input: regex_config.header_input,
+            match_target: regex_config.match_target,
         },
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rsa::{
+        pkcs1::DecodeRsaPrivateKey,
+        pkcs8::{EncodePublicKey, LineEnding},
+        RsaPrivateKey,
+    };
+    use zkemail_core::{verify_email, CommitmentVersion, TEST_PRIVATE_KEY};
+
+    use super::*;
+    use crate::{sign_email, Canonicalization};
+
+    #[tokio::test]
+    async fn test_verify_email_full_with_local_key() {
+        let private_key = RsaPrivateKey::from_pkcs1_pem(TEST_PRIVATE_KEY).unwrap();
+        let store = std::env::temp_dir().join(format!("zkemail-full-{}", std::process::id()));
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(
+            store.join("s1._domainkey.example.invalid.pem"),
+            private_key
+                .to_public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap(),
+        )
+        .unwrap();
+
+        let signed = sign_email(
+            b"From: alice@example.invalid\r\nSubject: Hi\r\n\r\nHello\r\n",
+            "s1",
+            "example.invalid",
+            &private_key,
+            Canonicalization::RelaxedRelaxed,
+        )
+        .unwrap();
+        let resolver = DkimResolver::new()
+            .with_backends(vec![])
+            .with_cache_ttl(Duration::ZERO)
+            .with_local_store(store.clone());
+
+        let output = verify_email_full_with_resolver(&signed, &resolver)
+            .await
+            .unwrap();
+        assert_eq!(
+            output.from_domain_hash,
+            CommitmentVersion::V1.hash_domain(b"example.invalid")
+        );
+        let email = find_verified_signature(&signed, SigningDomain::Any, None, &resolver)
+            .await
+            .unwrap();
+        assert_eq!(output, verify_email(&email));
+
+        let tampered = [&signed[..signed.len() - 3], b"!\r\n"].concat();
+        assert!(verify_email_full_with_resolver(&tampered, &resolver)
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(store).unwrap();
+    }
+
+    #[test]
+    fn test_signature_candidates_prefer_from_domain() {
+        let email = b"DKIM-Signature: v=1; a=rsa-sha256; d=esp.example; s=s1; h=from; bh=; b=\r\n\
+DKIM-Signature: v=1; a=rsa-sha256; d=Example.com; s=s2; h=from; bh=; b=\r\n\
+From: alice@example.com\r\n\
+\r\n\
+Hello\r\n";
+        let candidate = |domain: &str, selector: &str| (domain.to_string(), selector.to_string());
+
+        assert_eq!(
+            signature_candidates(email, SigningDomain::Any).unwrap(),
+            vec![
+                candidate("example.com", "s2"),
+                candidate("esp.example", "s1")
+            ]
+        );
+        assert_eq!(
+            signature_candidates(email, SigningDomain::From).unwrap(),
+            vec![candidate("example.com", "s2")]
+        );
+        assert_eq!(
+            signature_candidates(email, SigningDomain::Exact("ESP.example")).unwrap(),
+            vec![candidate("ESP.example", "s1")]
+        );
+
+        let unaligned =
+            b"DKIM-Signature: v=1; a=rsa-sha256; d=esp.example; s=s1; h=from; bh=; b=\r\n\
+From: alice@example.com\r\n\
+\r\n";
+        assert_eq!(
+            signature_candidates(unaligned, SigningDomain::Any).unwrap(),
+            vec![candidate("esp.example", "s1")]
+        );
+        assert!(signature_candidates(unaligned, SigningDomain::From)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -30,3 +30,40 @@ impl AbiDecodable for VerificationOutput {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email_output() -> EmailVerifierOutput {
+        EmailVerifierOutput {
+            from_domain_hash: vec![1; 32],
+            public_key_hash: vec![2; 32],
+            external_inputs: vec!["name".to_string(), "value".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_abi_round_trip_email_only() {
+        let output = VerificationOutput::EmailOnly(email_output());
+        let encoded = output.abi_encode().unwrap();
+        assert_eq!(VerificationOutput::abi_decode(&encoded).unwrap(), output);
+    }
+
+    #[test]
+    fn test_abi_round_trip_with_regex() {
+        let output = VerificationOutput::WithRegex {
+            email: email_output(),
+            matches: vec!["$1,234.56".to_string()],
+        };
+        let encoded = output.abi_encode().unwrap();
+        assert_eq!(VerificationOutput::abi_decode(&encoded).unwrap(), output);
+    }
+
+    #[test]
+    fn test_abi_encode_rejects_invalid_hash_length() {
+        let mut email = email_output();
+        email.public_key_hash.pop();
+        assert!(VerificationOutput::EmailOnly(email).abi_encode().is_err());
+    }
+}
@@ -1,12 +1,21 @@
+mod builder;
 mod dkim;
+mod dmarc;
 mod email;
 mod file;
 mod generator;
 mod io;
+pub mod presets;
 mod regex;
+mod sign;
 mod structs;
 
+pub use builder::*;
+pub use dkim::*;
+pub use dmarc::*;
 pub use file::*;
 pub use generator::*;
 pub use io::*;
+pub use regex::*;
+pub use sign::*;
 pub use structs::*;
@@ -0,0 +1,79 @@
+//! Ready-made [`RegexPattern`]s for fields commonly extracted from emails.
+//!
+//! Each pattern captures the field itself in group 1, so it can be used as-is in the
+//! `header_parts` or `body_parts` of a [`crate::RegexConfig`]. Like any part, a preset must match
+//! the searched input exactly once.
+
+use crate::RegexPattern;
+
+fn preset(pattern: &str) -> RegexPattern {
+    RegexPattern {
+        pattern: pattern.to_string(),
+        capture_indices: Some(vec![1]),
+        capture_names: None,
+        must_not_match: false,
+        header_name: None,
+    }
+}
+
+/// A dollar amount with cents, e.g. `$1,234.56`, capturing `1,234.56`.
+pub fn amount_usd() -> RegexPattern {
+    preset(r"\$([0-9]{1,3}(?:,[0-9]{3})*\.[0-9]{2})")
+}
+
+/// A `Transaction ID: ...` line, capturing the uppercase alphanumeric ID.
+pub fn transaction_id() -> RegexPattern {
+    preset(r"Transaction ID: ([A-Z0-9]+)")
+}
+
+/// An ISO 8601 calendar date, e.g. `2024-03-15`.
+pub fn iso_date() -> RegexPattern {
+    preset(r"([0-9]{4}-(?:0[1-9]|1[0-2])-(?:0[1-9]|[12][0-9]|3[01]))")
+}
+
+/// An email address, e.g. `alice@example.com`.
+pub fn email_address() -> RegexPattern {
+    preset(r"([A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,})")
+}
+
+/// An order reference such as `Order #A1234`, `Order number: 123-456` or `Order No. 98765`,
+/// capturing the uppercase alphanumeric number.
+pub fn order_number() -> RegexPattern {
+    preset(r"[Oo]rder (?:#|[Nn]umber:? #?|[Nn]o\. ?)([A-Z0-9][A-Z0-9-]{3,})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex::compile_regex_parts;
+
+    fn capture(pattern: RegexPattern, input: &str) -> String {
+        compile_regex_parts(&[pattern], input.as_bytes()).unwrap()[0]
+            .captures
+            .as_ref()
+            .unwrap()[0]
+            .clone()
+    }
+
+    #[test]
+    fn test_presets_capture_fields() {
+        assert_eq!(capture(amount_usd(), "Total: $1,234.56\r\n"), "1,234.56");
+        assert_eq!(
+            capture(transaction_id(), "Transaction ID: TX9A7B\r\n"),
+            "TX9A7B"
+        );
+        assert_eq!(capture(iso_date(), "Shipped on 2024-03-15."), "2024-03-15");
+        assert_eq!(
+            capture(email_address(), "Contact alice.b+tag@mail.example.com now"),
+            "alice.b+tag@mail.example.com"
+        );
+        assert_eq!(
+            capture(order_number(), "Your order #A1234 has shipped"),
+            "A1234"
+        );
+        assert_eq!(
+            capture(order_number(), "Order number: 123-456\r\n"),
+            "123-456"
+        );
+    }
+}
@@ -1,51 +1,228 @@
 use anyhow::{anyhow, Result};
 use regex_automata::{dfa::regex::Regex as DFARegex, meta::Regex as MetaRegex};
-use zkemail_core::{CompiledRegex, DFA};
+use zkemail_core::{header_value_range, serialize_dfa, CompiledRegex, Endianness, DFA};
 
 use crate::structs::RegexPattern;
 
+/// Serializes a regex's DFAs in little-endian byte order, the default.
 pub fn create_dfa(re: &DFARegex) -> DFA {
-    let (fwd, fwd_pad) = re.forward().to_bytes_little_endian();
-    let (bwd, bwd_pad) = re.reverse().to_bytes_little_endian();
-    DFA {
-        fwd: fwd[fwd_pad..].to_vec(),
-        bwd: bwd[bwd_pad..].to_vec(),
-    }
+    create_dfa_with_endianness(re, Endianness::Little)
+}
+
+/// Serializes a regex's DFAs in the given byte order, which must be that of the target that
+/// loads them. The byte order is recorded in the returned [`DFA`].
+pub fn create_dfa_with_endianness(re: &DFARegex, endianness: Endianness) -> DFA {
+    serialize_dfa(re, endianness)
+}
+
+/// The outcome of one pattern in [`compile_regex_parts_report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartResult {
+    pub pattern: String,
+    /// Whether [`compile_regex_parts`] would accept the part: the pattern matched exactly once,
+    /// or never for a `must_not_match` part.
+    pub matched: bool,
+    /// How many non-overlapping matches the pattern has in the searched input.
+    pub match_count: usize,
+    /// The selected capture groups of the first match.
+    pub captures: Vec<String>,
+    /// Why the part could not be checked at all, e.g. an invalid pattern or a missing header.
+    pub error: Option<String>,
 }
 
 pub fn compile_regex_parts(parts: &[RegexPattern], input: &[u8]) -> Result<Vec<CompiledRegex>> {
     parts
         .iter()
         .map(|part| {
+            let input = part_input(part, input)?;
+
             let verify_dfa_re = DFARegex::new(&part.pattern)?;
+            if part.must_not_match {
+                if verify_dfa_re.is_match(input) {
+                    return Err(anyhow!("Input matches negative regex pattern: {:?}", part));
+                }
+                return Ok(CompiledRegex {
+                    verify_re: create_dfa(&verify_dfa_re),
+                    captures: None,
+                    must_not_match: true,
+                    header_name: part.header_name.clone(),
+                    compressed: false,
+                });
+            }
+
             if verify_dfa_re.find_iter(input).count() != 1 {
                 return Err(anyhow!("Input doesn't match regex pattern: {:?}", part));
             }
 
-            let verify_meta_re = MetaRegex::new(&part.pattern)?;
-            let mut caps = verify_meta_re.create_captures();
-            verify_meta_re.captures(input, &mut caps);
-
-            let captured_strings = if let Some(captures) = &part.capture_indices {
-                let results: Result<Vec<String>, _> = captures
-                    .iter()
-                    .map(|i| {
-                        caps.get_group(*i)
-                            .map(|capture| {
-                                String::from_utf8_lossy(&input[capture.range()]).into_owned()
-                            })
-                            .ok_or_else(|| anyhow!("Capture group not found"))
-                    })
-                    .collect();
-                results?
-            } else {
-                Vec::new()
-            };
-
             Ok(CompiledRegex {
                 verify_re: create_dfa(&verify_dfa_re),
-                captures: Some(captured_strings),
+                captures: Some(extract_captures(part, input)?),
+                must_not_match: false,
+                header_name: part.header_name.clone(),
+                compressed: false,
             })
         })
         .collect()
 }
+
+/// Checks every part against `input` like [`compile_regex_parts`], reporting each part's outcome
+/// instead of stopping at the first failure, so a config author can see all problems at once.
+pub fn compile_regex_parts_report(parts: &[RegexPattern], input: &[u8]) -> Vec<PartResult> {
+    parts
+        .iter()
+        .map(|part| {
+            let mut result = PartResult {
+                pattern: part.pattern.clone(),
+                matched: false,
+                match_count: 0,
+                captures: Vec::new(),
+                error: None,
+            };
+            let checked = part_input(part, input).and_then(|input| {
+                let match_count = DFARegex::new(&part.pattern)?.find_iter(input).count();
+                let captures = match match_count {
+                    0 => Vec::new(),
+                    _ => extract_captures(part, input)?,
+                };
+                Ok((match_count, captures))
+            });
+
+            match checked {
+                Ok((match_count, captures)) => {
+                    result.matched = match_count == usize::from(!part.must_not_match);
+                    result.match_count = match_count;
+                    result.captures = captures;
+                }
+                Err(e) => result.error = Some(e.to_string()),
+            }
+            result
+        })
+        .collect()
+}
+
+/// Narrows `input` to the value of the part's `header_name`, if set.
+fn part_input<'a>(part: &RegexPattern, input: &'a [u8]) -> Result<&'a [u8]> {
+    match &part.header_name {
+        Some(name) => header_value_range(input, name)
+            .map(|range| &input[range])
+            .ok_or_else(|| anyhow!("Header {} not found", name)),
+        None => Ok(input),
+    }
+}
+
+/// Returns the part's selected capture groups of the first match in `input`.
+fn extract_captures(part: &RegexPattern, input: &[u8]) -> Result<Vec<String>> {
+    let verify_meta_re = MetaRegex::new(&part.pattern)?;
+    let mut caps = verify_meta_re.create_captures();
+    verify_meta_re.captures(input, &mut caps);
+
+    part.capture_group_indices(&verify_meta_re)?
+        .unwrap_or_default()
+        .iter()
+        .map(|i| {
+            caps.get_group(*i)
+                .map(|capture| String::from_utf8_lossy(&input[capture.range()]).into_owned())
+                .ok_or_else(|| anyhow!("Capture group not found"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(
+        capture_indices: Option<Vec<usize>>,
+        capture_names: Option<Vec<&str>>,
+    ) -> RegexPattern {
+        RegexPattern {
+            pattern: r"Amount: \$(?P<dollars>[0-9]+)\.(?P<cents>[0-9]{2})".to_string(),
+            capture_indices,
+            capture_names: capture_names.map(|names| names.into_iter().map(String::from).collect()),
+            must_not_match: false,
+            header_name: None,
+        }
+    }
+
+    fn part(pattern: &str) -> RegexPattern {
+        RegexPattern {
+            pattern: pattern.to_string(),
+            capture_indices: Some(vec![1]),
+            capture_names: None,
+            must_not_match: false,
+            header_name: None,
+        }
+    }
+
+    #[test]
+    fn test_compile_regex_parts_with_capture_names() {
+        let input = b"Amount: $42.50\r\n";
+        let captures =
+            |part| compile_regex_parts(&[part], input).map(|parts| parts[0].captures.clone());
+
+        assert_eq!(
+            captures(pattern(None, Some(vec!["cents", "dollars"]))).unwrap(),
+            Some(vec!["50".to_string(), "42".to_string()])
+        );
+        assert_eq!(
+            captures(pattern(Some(vec![1]), None)).unwrap(),
+            Some(vec!["42".to_string()])
+        );
+        assert!(captures(pattern(None, Some(vec!["total"]))).is_err());
+        assert!(captures(pattern(Some(vec![1]), Some(vec!["cents"]))).is_err());
+    }
+
+    #[test]
+    fn test_create_dfa_with_endianness() {
+        let re = DFARegex::new("[0-9]+").unwrap();
+        let little = create_dfa(&re);
+        let big = create_dfa_with_endianness(&re, Endianness::Big);
+
+        assert_eq!(little.endianness, Endianness::Little);
+        assert_eq!(big.endianness, Endianness::Big);
+        assert_eq!(little.fwd.len(), big.fwd.len());
+        assert_ne!(little.fwd, big.fwd);
+    }
+
+    #[test]
+    fn test_compiled_dfa_matches_core_serialization() {
+        let input = b"Order 42 shipped";
+        let compiled = compile_regex_parts(&[part("Order ([0-9]+)")], input).unwrap();
+
+        let core_dfa = serialize_dfa(
+            &DFARegex::new("Order ([0-9]+)").unwrap(),
+            Endianness::Little,
+        );
+        assert_eq!(compiled[0].verify_re.fwd, core_dfa.fwd);
+        assert_eq!(compiled[0].verify_re.bwd, core_dfa.bwd);
+        assert_eq!(
+            zkemail_core::process_regex_parts(&compiled, input),
+            (true, vec!["42".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_compile_regex_parts_report() {
+        let parts = [
+            part(r"Amount: \$([0-9]+)"),
+            part(r"Item ([A-Z])"),
+            part(r"Ref: ([0-9]+)"),
+            RegexPattern {
+                must_not_match: true,
+                ..part(r"(CANCELLED)")
+            },
+            part(r"Bad ("),
+        ];
+
+        let report = compile_regex_parts_report(&parts, b"Amount: $42\r\nItem A\r\nItem B\r\n");
+        assert!(report[0].matched);
+        assert_eq!(report[0].captures, ["42"]);
+        assert!(!report[1].matched);
+        assert_eq!(report[1].match_count, 2);
+        assert_eq!(report[1].captures, ["A"]);
+        assert!(!report[2].matched);
+        assert_eq!(report[2].match_count, 0);
+        assert!(report[3].matched);
+        assert!(report[4].error.is_some());
+    }
+}
@@ -1,13 +1,338 @@
+use std::fmt;
+
+use regex_automata::{dfa::regex::Regex as DFARegex, meta::Regex as MetaRegex, PatternID};
 use serde::{Deserialize, Serialize};
+use zkemail_core::{HeaderInput, MatchTarget};
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RegexPattern {
     pub pattern: String,
     pub capture_indices: Option<Vec<usize>>,
+    /// Named groups to capture, e.g. `amount` for `(?P<amount>...)`. Cannot be combined with
+    /// `capture_indices`.
+    #[serde(default)]
+    pub capture_names: Option<Vec<String>>,
+    /// When set, the pattern must match zero times instead of exactly once.
+    #[serde(default)]
+    pub must_not_match: bool,
+    /// For header parts, matches only against the value of this header instead of the whole
+    /// canonicalized header block.
+    #[serde(default)]
+    pub header_name: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RegexConfig {
     pub header_parts: Option<Vec<RegexPattern>>,
     pub body_parts: Option<Vec<RegexPattern>>,
+    /// Whether header parts match the canonicalized signed headers (the default) or the raw
+    /// header block.
+    #[serde(default)]
+    pub header_input: HeaderInput,
+    /// Whether body parts match the body as signed (the default) or its decoded text.
+    #[serde(default)]
+    pub match_target: MatchTarget,
+}
+
+/// Why a [`RegexConfig`] is invalid, as returned by [`RegexConfig::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Neither `header_parts` nor `body_parts` contains a pattern.
+    NoParts,
+    /// The pattern does not compile to a DFA.
+    InvalidPattern { pattern: String, error: String },
+    /// A capture index refers to a group the pattern does not have. `group_count` includes the
+    /// implicit group 0 for the whole match.
+    CaptureIndexOutOfRange {
+        pattern: String,
+        index: usize,
+        group_count: usize,
+    },
+    /// A capture name refers to a named group the pattern does not have.
+    UnknownCaptureName { pattern: String, name: String },
+    /// The pattern sets both `capture_indices` and `capture_names`.
+    MixedCaptureSelectors { pattern: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoParts => write!(f, "Regex config has no header or body parts"),
+            Self::InvalidPattern { pattern, error } => {
+                write!(f, "Invalid regex pattern {:?}: {}", pattern, error)
+            }
+            Self::CaptureIndexOutOfRange {
+                pattern,
+                index,
+                group_count,
+            } => write!(
+                f,
+                "Capture index {} is out of range for pattern {:?} with {} groups",
+                index, pattern, group_count
+            ),
+            Self::UnknownCaptureName { pattern, name } => {
+                write!(
+                    f,
+                    "Pattern {:?} has no capture group named {}",
+                    pattern, name
+                )
+            }
+            Self::MixedCaptureSelectors { pattern } => write!(
+                f,
+                "Pattern {:?} sets both capture_indices and capture_names",
+                pattern
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl RegexConfig {
+    /// Checks that the config has at least one part, that every pattern compiles and that every
+    /// capture index refers to an existing group, without needing an email to match against.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let parts: Vec<&RegexPattern> = self
+            .header_parts
+            .iter()
+            .chain(&self.body_parts)
+            .flatten()
+            .collect();
+        if parts.is_empty() {
+            return Err(ConfigError::NoParts);
+        }
+        parts.into_iter().try_for_each(RegexPattern::validate)
+    }
+}
+
+/// Assembles a [`RegexConfig`] in Rust, validating each pattern as it is added.
+///
+/// ```ignore
+/// let config = RegexConfigBuilder::new()
+///     .header_pattern(r"subject:Order ([0-9]+)", vec![1])?
+///     .body_pattern(r"Total: \$([0-9.]+)", vec![1])?
+///     .build()?;
+/// ```
+#[derive(Debug, Default)]
+pub struct RegexConfigBuilder {
+    header_parts: Vec<RegexPattern>,
+    body_parts: Vec<RegexPattern>,
+    header_input: HeaderInput,
+    match_target: MatchTarget,
+}
+
+impl RegexConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a header part capturing the groups at `capture_indices`.
+    pub fn header_pattern(
+        self,
+        pattern: impl Into<String>,
+        capture_indices: Vec<usize>,
+    ) -> Result<Self, ConfigError> {
+        self.header_part(RegexPattern::with_captures(pattern, capture_indices))
+    }
+
+    /// Adds a body part capturing the groups at `capture_indices`.
+    pub fn body_pattern(
+        self,
+        pattern: impl Into<String>,
+        capture_indices: Vec<usize>,
+    ) -> Result<Self, ConfigError> {
+        self.body_part(RegexPattern::with_captures(pattern, capture_indices))
+    }
+
+    /// Adds a fully specified header part, such as a [`crate::presets`] pattern.
+    pub fn header_part(mut self, part: RegexPattern) -> Result<Self, ConfigError> {
+        part.validate()?;
+        self.header_parts.push(part);
+        Ok(self)
+    }
+
+    /// Adds a fully specified body part, such as a [`crate::presets`] pattern.
+    pub fn body_part(mut self, part: RegexPattern) -> Result<Self, ConfigError> {
+        part.validate()?;
+        self.body_parts.push(part);
+        Ok(self)
+    }
+
+    pub fn with_header_input(mut self, header_input: HeaderInput) -> Self {
+        self.header_input = header_input;
+        self
+    }
+
+    pub fn with_match_target(mut self, match_target: MatchTarget) -> Self {
+        self.match_target = match_target;
+        self
+    }
+
+    /// Returns the config, or [`ConfigError::NoParts`] if no part was added.
+    pub fn build(self) -> Result<RegexConfig, ConfigError> {
+        if self.header_parts.is_empty() && self.body_parts.is_empty() {
+            return Err(ConfigError::NoParts);
+        }
+        let non_empty = |parts: Vec<RegexPattern>| (!parts.is_empty()).then_some(parts);
+        Ok(RegexConfig {
+            header_parts: non_empty(self.header_parts),
+            body_parts: non_empty(self.body_parts),
+            header_input: self.header_input,
+            match_target: self.match_target,
+        })
+    }
+}
+
+impl RegexPattern {
+    fn with_captures(pattern: impl Into<String>, capture_indices: Vec<usize>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            capture_indices: Some(capture_indices),
+            capture_names: None,
+            must_not_match: false,
+            header_name: None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |error: &dyn fmt::Display| ConfigError::InvalidPattern {
+            pattern: self.pattern.clone(),
+            error: error.to_string(),
+        };
+        DFARegex::new(&self.pattern).map_err(|e| invalid(&e))?;
+        let meta_re = MetaRegex::new(&self.pattern).map_err(|e| invalid(&e))?;
+        let group_count = meta_re.captures_len();
+
+        match self
+            .capture_group_indices(&meta_re)?
+            .unwrap_or_default()
+            .into_iter()
+            .find(|&index| index >= group_count)
+        {
+            Some(index) => Err(ConfigError::CaptureIndexOutOfRange {
+                pattern: self.pattern.clone(),
+                index,
+                group_count,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the capture group indices selected by `capture_indices` or, resolved against
+    /// `meta_re`, by `capture_names`. Setting both is rejected.
+    pub(crate) fn capture_group_indices(
+        &self,
+        meta_re: &MetaRegex,
+    ) -> Result<Option<Vec<usize>>, ConfigError> {
+        match (&self.capture_indices, &self.capture_names) {
+            (Some(_), Some(_)) => Err(ConfigError::MixedCaptureSelectors {
+                pattern: self.pattern.clone(),
+            }),
+            (Some(indices), None) => Ok(Some(indices.clone())),
+            (None, Some(names)) => names
+                .iter()
+                .map(|name| {
+                    meta_re
+                        .group_info()
+                        .to_index(PatternID::ZERO, name)
+                        .ok_or_else(|| ConfigError::UnknownCaptureName {
+                            pattern: self.pattern.clone(),
+                            name: name.clone(),
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(body_parts: &str) -> RegexConfig {
+        serde_json::from_str(&format!(r#"{{"body_parts": {}}}"#, body_parts)).unwrap()
+    }
+
+    #[test]
+    fn test_validate_regex_config() {
+        assert_eq!(
+            config(r#"[{"pattern": "Amount: (\\$[0-9]+)", "capture_indices": [1]}]"#).validate(),
+            Ok(())
+        );
+        assert_eq!(config("[]").validate(), Err(ConfigError::NoParts));
+        assert!(matches!(
+            config(r#"[{"pattern": "Amount: ([0-9]+", "capture_indices": []}]"#).validate(),
+            Err(ConfigError::InvalidPattern { .. })
+        ));
+        assert_eq!(
+            config(r#"[{"pattern": "Amount: ([0-9]+)", "capture_indices": [2]}]"#).validate(),
+            Err(ConfigError::CaptureIndexOutOfRange {
+                pattern: "Amount: ([0-9]+)".to_string(),
+                index: 2,
+                group_count: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_capture_names() {
+        let pattern = r#""pattern": "Amount: (?P<amount>[0-9]+)""#;
+        assert_eq!(
+            config(&format!(
+                r#"[{{{}, "capture_names": ["amount"]}}]"#,
+                pattern
+            ))
+            .validate(),
+            Ok(())
+        );
+        assert!(matches!(
+            config(&format!(r#"[{{{}, "capture_names": ["total"]}}]"#, pattern)).validate(),
+            Err(ConfigError::UnknownCaptureName { name, .. }) if name == "total"
+        ));
+        assert!(matches!(
+            config(&format!(
+                r#"[{{{}, "capture_names": ["amount"], "capture_indices": [1]}}]"#,
+                pattern
+            ))
+            .validate(),
+            Err(ConfigError::MixedCaptureSelectors { .. })
+        ));
+    }
+
+    #[test]
+    fn test_regex_config_builder() {
+        let config = RegexConfigBuilder::new()
+            .body_pattern(r"Amount: (\$[0-9]+)", vec![1])
+            .unwrap()
+            .with_match_target(MatchTarget::DecodedText)
+            .build()
+            .unwrap();
+        assert!(config.header_parts.is_none());
+        assert_eq!(config.body_parts.as_ref().unwrap().len(), 1);
+        assert_eq!(config.match_target, MatchTarget::DecodedText);
+        assert_eq!(config.validate(), Ok(()));
+
+        assert!(matches!(
+            RegexConfigBuilder::new().header_pattern("subject:([0-9]+", vec![1]),
+            Err(ConfigError::InvalidPattern { .. })
+        ));
+        assert!(matches!(
+            RegexConfigBuilder::new().body_pattern("Amount: ([0-9]+)", vec![2]),
+            Err(ConfigError::CaptureIndexOutOfRange { index: 2, .. })
+        ));
+        assert_eq!(
+            RegexConfigBuilder::new().build().unwrap_err(),
+            ConfigError::NoParts
+        );
+    }
+
+    #[test]
+    fn test_regex_config_rejects_unknown_fields() {
+        let typo = r#"{"body_parts": [{"pattern": "a", "capture_indice": [0]}]}"#;
+        assert!(serde_json::from_str::<RegexConfig>(typo).is_err());
+    }
 }